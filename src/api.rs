@@ -0,0 +1,378 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::database;
+use crate::error::Error;
+use crate::report::Report;
+use crate::services::disk::Disk;
+use crate::services::movie::Movie;
+use crate::services::protection::Protection;
+use crate::services::rule::Rule;
+use crate::services::serie::Serie;
+use crate::services::service_config::{ConnectionTest, SERVICE_KINDS, ServiceConfig};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+// an error answered with its status code and a JSON body
+struct ApiError {
+    status: u16,
+    body: Value
+}
+
+impl ApiError {
+    fn new(status: u16, message: &str) -> Self {
+        ApiError { status, body: json!({ "error": message }) }
+    }
+
+    fn not_found(message: &str) -> Self {
+        ApiError::new(404, message)
+    }
+
+    fn bad_request(message: &str) -> Self {
+        ApiError::new(400, message)
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for ApiError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        ApiError::new(500, &err.to_string())
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        let status = match err {
+            Error::NotFound(_) => 404,
+            Error::Invalid(_) => 400,
+            // the failure comes from a service called by the API
+            Error::Http { .. } | Error::Auth { .. } | Error::Timeout { .. } | Error::Unreachable { .. } | Error::Decode(_) => 502,
+            Error::MissingField { .. } | Error::Database(_) => 500
+        };
+        ApiError::new(status, &err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiError::new(500, &err.to_string())
+    }
+}
+
+// the body of a service creation or update, missing fields are left unchanged on update
+#[derive(Deserialize)]
+struct ServiceBody {
+    url: Option<String>,
+    api_key: Option<String>,
+    is4k: Option<bool>,
+    // jellyfin only, playback_reporting or user_data
+    activity_source: Option<String>,
+    // save even if the connection test fails
+    #[serde(default)]
+    force: bool
+}
+
+// serve the API until the stop flag is raised
+pub fn serve(address: &str, database_path: &str, stop: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
+    // let the front end call the API from another origin
+    let cors_headers = match std::env::var("API_CORS_ORIGIN") {
+        Ok(origin) => vec![
+            Header::from_bytes("Access-Control-Allow-Origin", origin.as_str()).map_err(|_| format!("Invalid API_CORS_ORIGIN : {}", origin))?,
+            Header::from_bytes("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE").unwrap(),
+            Header::from_bytes("Access-Control-Allow-Headers", "Authorization, Content-Type").unwrap()
+        ],
+        Err(_) => Vec::new()
+    };
+    let token = std::env::var("API_TOKEN").ok().filter(|token| !token.is_empty());
+    if token.is_none() {
+        println!("API_TOKEN is not set, only the GET requests are served");
+    }
+
+    let server = Server::http(address).map_err(|err| format!("Could not listen on {} : {}", address, err))?;
+    // a connection of its own, so the API answers while a phase is running
+    let conn = database::open_database(database_path)?;
+    println!("API listening on {}", address);
+
+    while !stop.load(Ordering::Relaxed) {
+        let mut request = match server.recv_timeout(Duration::from_secs(1))? {
+            Some(request) => request,
+            None => continue
+        };
+
+        let authorization = request.headers().iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.as_str().to_string());
+        let (status, body) = match authorize(request.method(), authorization.as_deref(), token.as_deref()).and_then(|_| handle(&conn, &mut request)) {
+            Ok(body) => (200, body.to_string()),
+            Err(err) => (err.status, err.body.to_string())
+        };
+        let mut response = Response::from_string(body)
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+        for header in &cors_headers {
+            response.add_header(header.clone());
+        }
+
+        match request.respond(response) {
+            Ok(_) => (),
+            Err(err) => println!("{:?}", err)
+        };
+    }
+
+    Ok(())
+}
+
+// with API_TOKEN every request needs it as bearer token, without it only the GET requests are served,
+// the CORS preflights never carry it
+fn authorize(method: &Method, authorization: Option<&str>, token: Option<&str>) -> Result<(), ApiError> {
+    if *method == Method::Options {
+        return Ok(());
+    }
+    let token = match token {
+        Some(token) => token,
+        None if *method == Method::Get => return Ok(()),
+        None => return Err(ApiError::new(403, "Set API_TOKEN to allow the requests changing data"))
+    };
+
+    if authorization.and_then(|authorization| authorization.strip_prefix("Bearer ")) != Some(token) {
+        return Err(ApiError::new(401, "Missing or invalid bearer token"));
+    }
+
+    Ok(())
+}
+
+fn handle(conn: &Connection, request: &mut Request) -> Result<Value, ApiError> {
+    let url = request.url().to_string();
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (url.as_str(), HashMap::new())
+    };
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let method = request.method().clone();
+
+    match (&method, segments.as_slice()) {
+        // CORS preflight
+        (Method::Options, _) => Ok(Value::Null),
+        (Method::Get, ["api", "v1", "movies"]) => list(to_values(Movie::get_all(conn)?)?, &query),
+        (Method::Get, ["api", "v1", "movies", tmdb_id]) => find(to_values(Movie::get_all(conn)?)?, "tmdb_id", tmdb_id),
+        (Method::Get, ["api", "v1", "series"]) => list(to_values(Serie::get_all(conn)?)?, &query),
+        (Method::Get, ["api", "v1", "series", tvdb_id]) => find(to_values(Serie::get_all(conn)?)?, "tvdb_id", tvdb_id),
+        (Method::Get, ["api", "v1", "disks"]) => list(get_disks(conn)?, &query),
+        (Method::Get, ["api", "v1", "disks", id]) => find(get_disks(conn)?, "id", id),
+        (Method::Get, ["api", "v1", "candidates"]) => list(get_candidates(conn)?, &query),
+        (Method::Get, ["api", "v1", "protections"]) => list(to_values(Protection::get_all(conn)?)?, &query),
+        (Method::Get, ["api", "v1", "services"]) => {
+            let mut configs = Vec::new();
+            for kind in SERVICE_KINDS {
+                configs.extend(ServiceConfig::get_all(conn, kind)?);
+            }
+            list(to_values(configs)?, &query)
+        },
+        (Method::Get, ["api", "v1", "services", kind]) => list(to_values(ServiceConfig::get_all(conn, check_kind(kind)?)?)?, &query),
+        (Method::Get, ["api", "v1", "services", kind, id]) => Ok(serde_json::to_value(get_service(conn, kind, id)?)?),
+        // test a configuration without saving it
+        (Method::Post, ["api", "v1", "services", kind, "test"]) => {
+            let body = read_service_body(request)?;
+            let config = ServiceConfig::new(check_kind(kind)?, &body.url.unwrap_or_default(), &body.api_key.unwrap_or_default(), body.is4k.unwrap_or(false));
+            Ok(serde_json::to_value(config.test())?)
+        },
+        (Method::Post, ["api", "v1", "services", kind, id, "test"]) => Ok(serde_json::to_value(get_service(conn, kind, id)?.test())?),
+        (Method::Post, ["api", "v1", "services", kind]) => {
+            let body = read_service_body(request)?;
+            let url = body.url.ok_or_else(|| ApiError::bad_request("Missing url"))?;
+            let mut config = ServiceConfig::new(check_kind(kind)?, &url, &body.api_key.unwrap_or_default(), body.is4k.unwrap_or(false));
+            if let Some(activity_source) = body.activity_source {
+                config.activity_source = activity_source;
+            }
+            save_service(conn, &mut config, body.force)
+        },
+        (Method::Put, ["api", "v1", "services", kind, id]) => {
+            let body = read_service_body(request)?;
+            let mut updated = get_service(conn, kind, id)?.update(body.url, body.api_key, body.is4k, body.activity_source)
+                .map_err(|err| ApiError::bad_request(&err.to_string()))?;
+            save_service(conn, &mut updated, body.force)
+        },
+        (Method::Delete, ["api", "v1", "services", kind, id]) => {
+            get_service(conn, kind, id)?.delete(conn)?;
+            Ok(json!({ "deleted": true }))
+        },
+        (Method::Get, _) => Err(ApiError::not_found(&format!("Unknown endpoint : {}", path))),
+        _ => Err(ApiError::new(405, &format!("Method {} not allowed on {}", method, path)))
+    }
+}
+
+fn check_kind(kind: &str) -> Result<&str, ApiError> {
+    if SERVICE_KINDS.contains(&kind) {
+        Ok(kind)
+    } else {
+        Err(ApiError::not_found(&format!("Unknown service kind : {}", kind)))
+    }
+}
+
+fn get_service(conn: &Connection, kind: &str, id: &str) -> Result<ServiceConfig, ApiError> {
+    let id = id.parse::<i32>().map_err(|_| ApiError::bad_request(&format!("Invalid id : {}", id)))?;
+    Ok(ServiceConfig::get_by_id(conn, check_kind(kind)?, id)?)
+}
+
+fn read_service_body(request: &mut Request) -> Result<ServiceBody, ApiError> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body).map_err(|err| ApiError::bad_request(&err.to_string()))?;
+    serde_json::from_str(&body).map_err(|err| ApiError::bad_request(&format!("Invalid body : {}", err)))
+}
+
+// test the connection before saving, a failed test is answered with 422 unless forced
+fn save_service(conn: &Connection, config: &mut ServiceConfig, force: bool) -> Result<Value, ApiError> {
+    let test: ConnectionTest = config.save_tested(conn, force)?;
+    if !test.is_success() && !force {
+        return Err(ApiError {
+            status: 422,
+            body: json!({ "error": test.error, "test": test })
+        });
+    }
+    Ok(json!({ "service": config, "test": test }))
+}
+
+fn to_values<T: Serialize>(items: Vec<T>) -> Result<Vec<Value>, ApiError> {
+    items.iter().map(|item| serde_json::to_value(item).map_err(|err| err.into())).collect()
+}
+
+// disks with the space used by their medias
+fn get_disks(conn: &Connection) -> Result<Vec<Value>, ApiError> {
+    let mut disks = Vec::new();
+    for disk in Disk::get_all(conn)? {
+        let mut value = serde_json::to_value(&disk)?;
+        value["name"] = json!(disk.get_name());
+        value["medias_size"] = json!(disk.get_medias_size(conn)?);
+        disks.push(value);
+    }
+    Ok(disks)
+}
+
+// every media copy the rules would delete, as in the dry run report,
+// the protected flags being the ones refreshed by the last deletion or protection change
+fn get_candidates(conn: &Connection) -> Result<Vec<Value>, ApiError> {
+    let rules = Rule::get_all(conn)?;
+    let movies = Movie::get_movies_to_delete(conn, &rules)?;
+    let series = Serie::get_series_to_delete(conn, &rules)?;
+    to_values(Report::new(conn, &rules, &movies, &series).items)
+}
+
+fn find(items: Vec<Value>, field: &str, id: &str) -> Result<Value, ApiError> {
+    items.into_iter()
+        .find(|item| is_equal(&item[field], id))
+        .ok_or_else(|| ApiError::not_found(&format!("{} {} not found", field, id)))
+}
+
+// filter, sort and paginate a list:
+// - "field=value" keeps the items whose field is equal to the value
+// - "q=text" keeps the items whose name or title contains the text
+// - "sort=field" or "sort=-field" for a descending order
+// - "page" starts at 1, "page_size" defaults to 50
+fn list(mut items: Vec<Value>, query: &HashMap<String, String>) -> Result<Value, ApiError> {
+    for (field, expected) in query {
+        match field.as_str() {
+            "page" | "page_size" | "sort" => (),
+            "q" => {
+                let text = expected.to_lowercase();
+                items.retain(|item| ["name", "title"].iter().any(|field| {
+                    item[field].as_str().is_some_and(|value| value.to_lowercase().contains(&text))
+                }));
+            },
+            _ => items.retain(|item| is_equal(&item[field], expected))
+        }
+    }
+
+    if let Some(sort) = query.get("sort") {
+        let (field, descending) = match sort.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (sort.as_str(), false)
+        };
+        items.sort_by(|a, b| {
+            let ordering = compare(&a[field], &b[field]);
+            if descending { ordering.reverse() } else { ordering }
+        });
+    }
+
+    let page = get_number(query, "page", 1)?.max(1);
+    let page_size = get_number(query, "page_size", DEFAULT_PAGE_SIZE)?.clamp(1, MAX_PAGE_SIZE);
+    let total = items.len();
+    let items: Vec<Value> = items.into_iter().skip((page - 1) * page_size).take(page_size).collect();
+
+    Ok(json!({
+        "items": items,
+        "total": total,
+        "page": page,
+        "page_size": page_size
+    }))
+}
+
+fn get_number(query: &HashMap<String, String>, name: &str, default: usize) -> Result<usize, ApiError> {
+    match query.get(name) {
+        Some(value) => value.parse::<usize>().map_err(|_| ApiError::bad_request(&format!("Invalid {} : {}", name, value))),
+        None => Ok(default)
+    }
+}
+
+fn is_equal(value: &Value, expected: &str) -> bool {
+    match value {
+        Value::String(value) => value.eq_ignore_ascii_case(expected),
+        Value::Array(values) => values.iter().any(|value| is_equal(value, expected)),
+        Value::Number(value) => expected.parse::<f64>().ok() == value.as_f64(),
+        Value::Bool(value) => expected.parse::<bool>().ok() == Some(*value),
+        _ => false
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> CmpOrdering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()).unwrap_or(CmpOrdering::Equal),
+        (Value::String(a), Value::String(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        _ => CmpOrdering::Equal
+    }
+}
+
+// decode "a=1&b=some%20text" into a map
+fn parse_query(query: &str) -> HashMap<String, String> {
+    reqwest::Url::parse(&format!("http://localhost/?{}", query))
+        .map(|url| url.query_pairs().into_owned().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(result: Result<(), ApiError>) -> u16 {
+        match result {
+            Ok(_) => 200,
+            Err(err) => err.status
+        }
+    }
+
+    #[test]
+    fn without_token_only_reads_are_served() {
+        assert_eq!(status(authorize(&Method::Get, None, None)), 200);
+        assert_eq!(status(authorize(&Method::Put, None, None)), 403);
+        assert_eq!(status(authorize(&Method::Post, Some("Bearer secret"), None)), 403);
+        assert_eq!(status(authorize(&Method::Options, None, None)), 200);
+    }
+
+    #[test]
+    fn with_token_every_request_needs_it() {
+        assert_eq!(status(authorize(&Method::Get, Some("Bearer secret"), Some("secret"))), 200);
+        assert_eq!(status(authorize(&Method::Put, Some("Bearer secret"), Some("secret"))), 200);
+        assert_eq!(status(authorize(&Method::Get, None, Some("secret"))), 401);
+        assert_eq!(status(authorize(&Method::Get, Some("Bearer other"), Some("secret"))), 401);
+        assert_eq!(status(authorize(&Method::Get, Some("secret"), Some("secret"))), 401);
+        assert_eq!(status(authorize(&Method::Options, None, Some("secret"))), 200);
+    }
+}
@@ -0,0 +1,22 @@
+const DEFAULT_CONFIG_PATH: &str = "lunager.conf";
+
+// get a setting of the CONFIG_PATH file, made of "key = value" lines and "#" comments
+pub fn get_setting(key: &str) -> Option<String> {
+    let path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    let content = std::fs::read_to_string(path).ok()?;
+
+    content.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(name, _)| name.trim() == key)
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+// get a setting from the command line option, then the environment variable, then the config file
+pub fn get(args: &[String], option: &str, variable: &str, key: &str) -> Option<String> {
+    args.iter().position(|arg| arg == option)
+        .and_then(|index| args.get(index + 1).cloned())
+        .or_else(|| std::env::var(variable).ok())
+        .or_else(|| get_setting(key))
+}
@@ -0,0 +1,148 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use rusqlite::Connection;
+
+use crate::api;
+use crate::phase::Phase;
+
+// every hour for the synchronisation, every day at 3am for the deletion
+const DEFAULT_SYNC_SCHEDULE: &str = "0 * * * *";
+const DEFAULT_DELETION_SCHEDULE: &str = "0 3 * * *";
+const DEFAULT_LOCK_PATH: &str = "lunager.lock";
+
+// a lock file holding the pid of the process running a pass, so two passes never overlap
+pub struct RunLock {
+    path: String
+}
+
+impl RunLock {
+    pub fn acquire() -> Result<RunLock, Box<dyn std::error::Error>> {
+        let path = std::env::var("LOCK_PATH").unwrap_or_else(|_| DEFAULT_LOCK_PATH.to_string());
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                write!(file, "{}", std::process::id())?;
+                Ok(RunLock { path })
+            },
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                let pid = std::fs::read_to_string(&path).unwrap_or_default();
+                if RunLock::is_running(pid.trim()) {
+                    return Err(format!("A pass is already running (pid {})", pid.trim()).into());
+                }
+
+                // the process holding the lock died without releasing it
+                println!("Removing stale lock of pid {}", pid.trim());
+                std::fs::remove_file(&path)?;
+                RunLock::acquire()
+            },
+            Err(err) => Err(err.into())
+        }
+    }
+
+    // a lock left by our own pid comes from a previous run, e.g. a restarted container
+    fn is_running(pid: &str) -> bool {
+        if pid.is_empty() || pid == std::process::id().to_string() {
+            return false;
+        }
+        // without procfs there is no way to tell, so the lock is kept
+        !Path::new("/proc/self").exists() || Path::new(&format!("/proc/{}", pid)).exists()
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(_) => (),
+            Err(err) => println!("{:?}", err)
+        };
+    }
+}
+
+// get the schedule of a phase from SCHEDULE_<PHASE>, with 5 (minutes) or 6 (seconds) fields cron expressions
+fn get_schedule(phase: &Phase) -> Result<Schedule, Box<dyn std::error::Error>> {
+    let default = if *phase == Phase::Deletion { DEFAULT_DELETION_SCHEDULE } else { DEFAULT_SYNC_SCHEDULE };
+    let expression = std::env::var(format!("SCHEDULE_{}", phase.get_name().to_uppercase())).unwrap_or_else(|_| default.to_string());
+
+    let expression = if expression.split_whitespace().count() == 5 { format!("0 {}", expression) } else { expression };
+    Schedule::from_str(&expression).map_err(|err| format!("Invalid schedule for {} : {} ({})", phase.get_name(), expression, err).into())
+}
+
+// a flag raised on SIGTERM or SIGINT
+pub fn register_stop() -> Result<Arc<AtomicBool>, Box<dyn std::error::Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&stop))?;
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&stop))?;
+    Ok(stop)
+}
+
+// run the phases on their schedules until SIGTERM or SIGINT, the running phase is finished before stopping
+pub fn run(conn: &Connection, database_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let stop = register_stop()?;
+
+    // serve the API next to the schedules if an address is configured
+    if let Ok(address) = std::env::var("API_ADDRESS") {
+        let stop = Arc::clone(&stop);
+        let database_path = database_path.to_string();
+        std::thread::spawn(move || {
+            match api::serve(&address, &database_path, stop) {
+                Ok(_) => (),
+                Err(err) => println!("{:?}", err)
+            };
+        });
+    }
+
+    let mut schedules: Vec<(Phase, Schedule, Option<DateTime<Utc>>)> = Vec::new();
+    for phase in Phase::ALL {
+        let schedule = get_schedule(&phase)?;
+        let next_run = schedule.upcoming(Utc).next();
+        match next_run {
+            Some(next_run) => println!("Next {} run : {}", phase.get_name(), next_run),
+            None => println!("No upcoming {} run", phase.get_name())
+        };
+        schedules.push((phase, schedule, next_run));
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        let now = Utc::now();
+        let due: Vec<Phase> = schedules.iter()
+            .filter(|(_, _, next_run)| next_run.is_some_and(|next_run| next_run <= now))
+            .map(|(phase, _, _)| *phase)
+            .collect();
+
+        if !due.is_empty() {
+            match RunLock::acquire() {
+                Ok(_lock) => {
+                    for phase in &due {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        phase.run(conn);
+                    }
+                },
+                // the pass is skipped, it will run on the next schedule
+                Err(err) => println!("{:?}", err)
+            };
+
+            // runs missed while the pass was running are skipped
+            let now = Utc::now();
+            for (phase, schedule, next_run) in schedules.iter_mut() {
+                if due.contains(phase) {
+                    *next_run = schedule.after(&now).next();
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    println!("Daemon stopped");
+    Ok(())
+}
@@ -1,8 +1,103 @@
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, OpenFlags};
 
-pub fn initialize_database() -> Result<()> {
-    let conn = Connection::open("data.db")?;
+use crate::error::{Error, Result};
 
+pub const DEFAULT_DATABASE_PATH: &str = "data.db";
+// an in-memory database, shared by the connections of the process
+pub const IN_MEMORY_DATABASE: &str = ":memory:";
+
+type Migration = fn(&Connection) -> Result<()>;
+
+// the migrations of the schema, in order, the user_version of a database is the number of migrations applied
+const MIGRATIONS: [(&str, Migration); 17] = [
+    ("create the tables", create_tables),
+    ("record the movie deletions", add_movie_deletions),
+    ("record the serie deletions", add_serie_deletions),
+    ("create the jellyfin item table", create_jellyfin_item_table),
+    ("create the rule tables", create_rule_tables),
+    ("add the disk watermarks", add_disk_watermarks),
+    ("identify the disks by mount point", identify_disks_by_mount_point),
+    ("remember the last view source", add_last_view_source),
+    ("create the protection table", create_protection_table),
+    ("store the media tags", add_media_tags),
+    ("fix the column types", fix_column_types),
+    ("index the rating keys and names", create_indexes),
+    ("register each radarr and sonarr once", deduplicate_instances),
+    ("key the jellyfin items by jellyfin instance", key_jellyfin_items_by_instance),
+    ("choose the jellyfin activity source", add_jellyfin_activity_source),
+    ("remember the last tautulli play", add_tautulli_last_history),
+    ("create the plex item table", create_plex_item_table)
+];
+
+// open the database and apply the missing migrations
+pub fn open_database(path: &str) -> Result<Connection> {
+    let conn = if path == IN_MEMORY_DATABASE {
+        Connection::open_with_flags("file:lunager?mode=memory&cache=shared", OpenFlags::default() | OpenFlags::SQLITE_OPEN_SHARED_CACHE)?
+    } else {
+        Connection::open(path)?
+    };
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+// apply each missing migration in its own transaction, a database migrated by a newer version is refused
+pub fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::Invalid(format!("The database is at version {}, newer than the {} supported, upgrade lunager", version, MIGRATIONS.len())));
+    }
+
+    for (index, (name, migration)) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        println!("Applied migration {} : {}", index + 1, name);
+    }
+
+    Ok(())
+}
+
+// the columns of a table
+fn get_columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get(1))?;
+
+    let mut names = Vec::new();
+    for column in columns {
+        names.push(column?);
+    }
+    Ok(names)
+}
+
+// databases created before the migrations can already have some of the columns added since
+fn add_missing_columns(conn: &Connection, table: &str, columns: &[(&str, &str)]) -> Result<()> {
+    let existing = get_columns(conn, table)?;
+    for (name, definition) in columns {
+        if !existing.iter().any(|column| column == name) {
+            conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, definition), [])?;
+        }
+    }
+    Ok(())
+}
+
+// copy a table into a new definition, as sqlite can not change the type of a column or drop one,
+// the columns missing from the new definition are dropped
+fn rebuild_table(conn: &Connection, table: &str, definition: &str) -> Result<()> {
+    conn.execute(&format!("CREATE TABLE {}_new ({})", table, definition), [])?;
+    let existing = get_columns(conn, table)?;
+    let columns = get_columns(conn, &format!("{}_new", table))?.into_iter()
+        .filter(|column| existing.contains(column))
+        .collect::<Vec<String>>()
+        .join(", ");
+    conn.execute(&format!("INSERT INTO {table}_new ({columns}) SELECT {columns} FROM {table}", table = table, columns = columns), [])?;
+    conn.execute(&format!("DROP TABLE {}", table), [])?;
+    conn.execute(&format!("ALTER TABLE {table}_new RENAME TO {table}", table = table), [])?;
+    Ok(())
+}
+
+// the schema of the first release, older databases already have these tables
+fn create_tables(conn: &Connection) -> Result<()> {
     // Create radarr, sonarr, jellyseerr and overseerr tables
     let services  = vec!["jellyseerr", "overseerr"];
     for service in &services {
@@ -50,7 +145,7 @@ pub fn initialize_database() -> Result<()> {
             PRIMARY KEY (radarr_id, path),
             FOREIGN KEY(radarr_id) REFERENCES radarr(id),
             FOREIGN KEY(disk_id) REFERENCES disk(id)
-        )", 
+        )",
         []
     )?;
 
@@ -63,7 +158,7 @@ pub fn initialize_database() -> Result<()> {
             PRIMARY KEY (sonarr_id, path),
             FOREIGN KEY(sonarr_id) REFERENCES sonarr(id),
             FOREIGN KEY(disk_id) REFERENCES disk(id)
-        )", 
+        )",
         []
     )?;
 
@@ -79,7 +174,7 @@ pub fn initialize_database() -> Result<()> {
             protected INTEGER,
             FOREIGN KEY(path_hd) REFERENCES radarr_path(path),
             FOREIGN KEY(path_4k) REFERENCES radarr_path(path)
-        )", 
+        )",
         []
     )?;
 
@@ -96,7 +191,7 @@ pub fn initialize_database() -> Result<()> {
             protected INTEGER,
             FOREIGN KEY(path_hd) REFERENCES sonarr_path(path),
             FOREIGN KEY(path_4k) REFERENCES sonarr_path(path)
-        )", 
+        )",
         []
     )?;
 
@@ -106,7 +201,7 @@ pub fn initialize_database() -> Result<()> {
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             url TEXT,
             api_key TEXT
-        )", 
+        )",
         []
     )?;
 
@@ -115,14 +210,296 @@ pub fn initialize_database() -> Result<()> {
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             url TEXT,
             api_key TEXT
-        )", 
+        )",
         []
     )?;
 
-    match conn.close() {
-        Ok(_) => (),
-        Err((_, err)) => println!("{}", err)
-    };
+    Ok(())
+}
+
+// the deleted movies are flagged and every deletion is logged
+fn add_movie_deletions(conn: &Connection) -> Result<()> {
+    add_missing_columns(conn, "movie", &[
+        ("deleted", "INTEGER DEFAULT 0")
+    ])?;
+
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS deletion (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            media_type TEXT,
+            media_id INTEGER,
+            name TEXT,
+            service_id INTEGER,
+            path TEXT,
+            date INTEGER,
+            success INTEGER,
+            error TEXT
+        )",
+        []
+    )?;
+    Ok(())
+}
+
+fn add_serie_deletions(conn: &Connection) -> Result<()> {
+    add_missing_columns(conn, "serie", &[
+        ("deleted", "INTEGER DEFAULT 0")
+    ])
+}
+
+// link between the jellyfin items and the movie (tmdb id) or serie (tvdb id) they belong to
+fn create_jellyfin_item_table(conn: &Connection) -> Result<()> {
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS jellyfin_item (
+            item_id TEXT PRIMARY KEY,
+            media_type TEXT,
+            media_id INTEGER
+        )",
+        []
+    )?;
+    Ok(())
+}
+
+// the rules and the added date and size of the copies they are evaluated against
+fn create_rule_tables(conn: &Connection) -> Result<()> {
+    for table in ["movie", "serie"] {
+        add_missing_columns(conn, table, &[
+            ("added", "INTEGER DEFAULT 0"),
+            ("size_hd", "INTEGER DEFAULT 0"),
+            ("size_4k", "INTEGER DEFAULT 0")
+        ])?;
+    }
+
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS rule (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT,
+            media_type TEXT DEFAULT '',
+            operator TEXT DEFAULT 'AND',
+            enabled INTEGER DEFAULT 1
+        )",
+        []
+    )?;
+
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS rule_condition (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            rule_id INTEGER,
+            field TEXT,
+            operator TEXT,
+            value TEXT,
+            FOREIGN KEY(rule_id) REFERENCES rule(id)
+        )",
+        []
+    )?;
+    Ok(())
+}
 
+fn add_disk_watermarks(conn: &Connection) -> Result<()> {
+    add_missing_columns(conn, "disk", &[
+        ("high_watermark", "INTEGER DEFAULT 0"),
+        ("low_watermark", "INTEGER DEFAULT 0")
+    ])
+}
+
+// the first disks were only identified by their free space, they are discovered again from their mount point
+// and the free space is now sampled in its own table
+fn identify_disks_by_mount_point(conn: &Connection) -> Result<()> {
+    add_missing_columns(conn, "disk", &[("key", "TEXT")])?;
+    for table in ["radarr_path", "sonarr_path"] {
+        conn.execute(&format!("UPDATE {} SET disk_id = NULL WHERE disk_id IN (SELECT id FROM disk WHERE key IS NULL)", table), [])?;
+    }
+    conn.execute("DELETE FROM disk WHERE key IS NULL", [])?;
+
+    // a disk is identified by his volume label or mount path and his total space
+    rebuild_table(conn, "disk", "
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        key TEXT UNIQUE,
+        path TEXT,
+        label TEXT,
+        total_space INTEGER,
+        high_watermark INTEGER DEFAULT 0,
+        low_watermark INTEGER DEFAULT 0
+    ")?;
+
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS disk_space (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            disk_id INTEGER,
+            date INTEGER,
+            free_space INTEGER,
+            FOREIGN KEY(disk_id) REFERENCES disk(id)
+        )",
+        []
+    )?;
+    Ok(())
+}
+
+fn add_last_view_source(conn: &Connection) -> Result<()> {
+    for table in ["movie", "serie"] {
+        add_missing_columns(conn, table, &[("last_view_source", "TEXT DEFAULT ''")])?;
+    }
+    Ok(())
+}
+
+fn create_protection_table(conn: &Connection) -> Result<()> {
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS protection (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            media_type TEXT,
+            media_id INTEGER DEFAULT 0,
+            title TEXT DEFAULT '',
+            until INTEGER DEFAULT 0,
+            protected_by TEXT DEFAULT '',
+            reason TEXT DEFAULT '',
+            date INTEGER
+        )",
+        []
+    )?;
+    Ok(())
+}
+
+// the radarr or sonarr tags of each copy
+fn add_media_tags(conn: &Connection) -> Result<()> {
+    for table in ["movie", "serie"] {
+        add_missing_columns(conn, table, &[
+            ("tags_hd", "TEXT DEFAULT ''"),
+            ("tags_4k", "TEXT DEFAULT ''")
+        ])?;
+    }
+    Ok(())
+}
+
+// fix the typos of the first schema: api_key TEXTf, INTERGER and the path_hd of the series
+fn fix_column_types(conn: &Connection) -> Result<()> {
+    for service in ["jellyseerr", "overseerr"] {
+        rebuild_table(conn, service, "
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT,
+            url TEXT,
+            api_key TEXT
+        ")?;
+    }
+
+    rebuild_table(conn, "movie", "
+        tmdb_id INTEGER PRIMARY KEY,
+        name TEXT,
+        path_hd TEXT,
+        path_4k TEXT,
+        rating_key TEXT,
+        last_view INTEGER,
+        last_view_source TEXT DEFAULT '',
+        protected INTEGER DEFAULT 0,
+        deleted INTEGER DEFAULT 0,
+        added INTEGER DEFAULT 0,
+        size_hd INTEGER DEFAULT 0,
+        size_4k INTEGER DEFAULT 0,
+        tags_hd TEXT DEFAULT '',
+        tags_4k TEXT DEFAULT '',
+        FOREIGN KEY(path_hd) REFERENCES radarr_path(path),
+        FOREIGN KEY(path_4k) REFERENCES radarr_path(path)
+    ")?;
+
+    rebuild_table(conn, "serie", "
+        tvdb_id INTEGER PRIMARY KEY,
+        name TEXT,
+        path_hd TEXT,
+        path_4k TEXT,
+        rating_key TEXT,
+        last_view INTEGER,
+        last_view_source TEXT DEFAULT '',
+        protected INTEGER DEFAULT 0,
+        deleted INTEGER DEFAULT 0,
+        added INTEGER DEFAULT 0,
+        size_hd INTEGER DEFAULT 0,
+        size_4k INTEGER DEFAULT 0,
+        tags_hd TEXT DEFAULT '',
+        tags_4k TEXT DEFAULT '',
+        FOREIGN KEY(path_hd) REFERENCES sonarr_path(path),
+        FOREIGN KEY(path_4k) REFERENCES sonarr_path(path)
+    ")?;
+
+    Ok(())
+}
+
+// the medias are looked up by rating key for tautulli and by name for jellyfin
+fn create_indexes(conn: &Connection) -> Result<()> {
+    for table in ["movie", "serie"] {
+        conn.execute(&format!("CREATE INDEX IF NOT EXISTS {table}_rating_key ON {table} (rating_key)", table = table), [])?;
+        conn.execute(&format!("CREATE INDEX IF NOT EXISTS {table}_name ON {table} (name)", table = table), [])?;
+    }
+    Ok(())
+}
+
+// radarr and sonarr were registered again on every discovery, only the latest registration of each url is kept
+fn deduplicate_instances(conn: &Connection) -> Result<()> {
+    for kind in ["radarr", "sonarr"] {
+        // the request manager which discovered the instance and its id there, empty when added by hand
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN server_id INTEGER DEFAULT 0", kind), [])?;
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN discovered_by TEXT DEFAULT ''", kind), [])?;
+
+        conn.execute(&format!("DELETE FROM {kind}_path WHERE {kind}_id NOT IN (SELECT id FROM {kind})", kind = kind), [])?;
+        conn.execute(&format!("
+            UPDATE OR REPLACE {kind}_path
+            SET {kind}_id = (
+                SELECT MAX(latest.id) FROM {kind} AS latest
+                WHERE latest.url = (SELECT url FROM {kind} WHERE id = {kind}_path.{kind}_id)
+            )
+        ", kind = kind), [])?;
+        conn.execute(&format!("DELETE FROM {kind} WHERE id NOT IN (SELECT MAX(id) FROM {kind} GROUP BY url)", kind = kind), [])?;
+        conn.execute(&format!("CREATE UNIQUE INDEX {kind}_url ON {kind} (url)", kind = kind), [])?;
+    }
+    Ok(())
+}
+
+// the jellyfin items were shared by every jellyfin and those not resolved were never requested again,
+// the resolved ones are kept for any jellyfin as they may come from jellyseerr
+fn key_jellyfin_items_by_instance(conn: &Connection) -> Result<()> {
+    conn.execute("
+        CREATE TABLE jellyfin_item_new (
+            jellyfin_id INTEGER DEFAULT 0,
+            item_id TEXT,
+            media_type TEXT,
+            media_id INTEGER,
+            date INTEGER DEFAULT 0,
+            PRIMARY KEY (jellyfin_id, item_id)
+        )",
+        []
+    )?;
+    conn.execute("
+        INSERT INTO jellyfin_item_new (jellyfin_id, item_id, media_type, media_id)
+        SELECT 0, item_id, media_type, media_id FROM jellyfin_item WHERE media_type != ''
+    ", [])?;
+    conn.execute("DROP TABLE jellyfin_item", [])?;
+    conn.execute("ALTER TABLE jellyfin_item_new RENAME TO jellyfin_item", [])?;
+    Ok(())
+}
+
+// the playback reporting plugin was the only jellyfin activity source
+fn add_jellyfin_activity_source(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE jellyfin ADD COLUMN activity_source TEXT DEFAULT 'playback_reporting'", [])?;
+    Ok(())
+}
+
+// the whole tautulli history was read on every run
+fn add_tautulli_last_history(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE tautulli ADD COLUMN last_history_id INTEGER DEFAULT 0", [])?;
+    conn.execute("ALTER TABLE tautulli ADD COLUMN last_history_date INTEGER DEFAULT 0", [])?;
+    Ok(())
+}
+
+// link between the plex rating keys seen by a tautulli and the movie (tmdb id) or serie (tvdb id) they belong to
+fn create_plex_item_table(conn: &Connection) -> Result<()> {
+    conn.execute("
+        CREATE TABLE plex_item (
+            tautulli_id INTEGER,
+            rating_key TEXT,
+            media_type TEXT,
+            media_id INTEGER,
+            date INTEGER DEFAULT 0,
+            PRIMARY KEY (tautulli_id, rating_key),
+            FOREIGN KEY(tautulli_id) REFERENCES tautulli(id)
+        )",
+        []
+    )?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+// errors of the services, so a down service, a refused api key and a malformed answer can be told apart
+#[derive(Debug)]
+pub enum Error {
+    // the service answered with an error status
+    Http { url: String, status: u16 },
+    // the service refused the api key
+    Auth { url: String, status: u16 },
+    // the service did not answer in time
+    Timeout { url: String },
+    // the service could not be reached
+    Unreachable { url: String, message: String },
+    // the answer is not the expected JSON
+    Decode(String),
+    // a field is missing or has an unexpected type
+    MissingField { field: String, context: String },
+    Database(rusqlite::Error),
+    NotFound(String),
+    // invalid input, such as a date, a configuration or an import file
+    Invalid(String)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn missing_field(field: &str, context: &str) -> Self {
+        Error::MissingField { field: field.to_string(), context: context.to_string() }
+    }
+
+    // classify an error status, 401 and 403 meaning the api key is refused
+    pub fn from_status(url: &str, status: u16) -> Self {
+        let url = strip_query(url);
+        match status {
+            401 | 403 => Error::Auth { url, status },
+            _ => Error::Http { url, status }
+        }
+    }
+
+    // classify a request that got no answer
+    pub fn from_reqwest(url: &str, err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Error::Timeout { url: strip_query(url) }
+        } else if let Some(status) = err.status() {
+            Error::from_status(url, status.as_u16())
+        } else if err.is_decode() {
+            Error::Decode(err.without_url().to_string())
+        } else {
+            Error::Unreachable { url: strip_query(url), message: err.without_url().to_string() }
+        }
+    }
+}
+
+// the query can hold an api key, as for tautulli
+fn strip_query(url: &str) -> String {
+    url.split('?').next().unwrap_or_default().to_string()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http { url, status } => write!(f, "Request failed: {} ({})", status, url),
+            Error::Auth { url, status } => write!(f, "Invalid api key: {} ({})", status, url),
+            Error::Timeout { url } => write!(f, "Request timed out ({})", url),
+            Error::Unreachable { url, message } => write!(f, "Could not reach {} : {}", url, message),
+            Error::Decode(message) => write!(f, "Invalid response : {}", message),
+            Error::MissingField { field, context } => write!(f, "Missing field {} in {}", field, context),
+            Error::Database(err) => write!(f, "Database error : {}", err),
+            Error::NotFound(message) => write!(f, "{} not found", message),
+            Error::Invalid(message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Database(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Database(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Decode(err.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(err: std::num::ParseIntError) -> Self {
+        Error::Invalid(err.to_string())
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        Error::Invalid(err.to_string())
+    }
+}
@@ -1,145 +1,197 @@
+mod api;
+mod config;
+mod daemon;
 mod database;
-use database::initialize_database;
+mod error;
+mod phase;
+mod pipeline;
+mod report;
+use database::DEFAULT_DATABASE_PATH;
 use rusqlite::Connection;
-use services::jellyfin::Jellyfin;
-use services::overseerr::Overseerr;
 
-use crate::services::movie::Movie;
-use crate::services::serie::Serie;
-use crate::services::tautulli::Tautulli;
+use crate::daemon::RunLock;
+use crate::error::{Error, Result};
+use crate::phase::Phase;
+use crate::services::protection::Protection;
+use crate::services::service_config::{SERVICE_KINDS, ServiceConfig};
 
 mod services;
 
+const DEFAULT_API_ADDRESS: &str = "127.0.0.1:8080";
+
 fn main() {
-    // Initialize the sqlite database
-    match initialize_database() {
-        Ok(_) => (),
-        Err(err) => println!("{:?}", err)
-    };
+    let mut args: Vec<String> = std::env::args().collect();
 
-    let conn = Connection::open("data.db").unwrap();
+    // the database is given by --database, DATABASE_PATH or database_path in the config file
+    let database_path = config::get(&args, "--database", "DATABASE_PATH", "database_path").unwrap_or_else(|| DEFAULT_DATABASE_PATH.to_string());
+    if let Some(index) = args.iter().position(|arg| arg == "--database") {
+        args.drain(index..(index + 2).min(args.len()));
+    }
 
-    println!("====================Overseerr====================");
-    let overseerr = Overseerr::get_first(&conn);
-    match overseerr.update_db_movies(&conn) {
-        Ok(_) => (),
-        Err(err) => println!("{:?}", err)
-    };
-    match overseerr.update_db_series(&conn) {
-        Ok(_) => (),
-        Err(err) => println!("{:?}", err)
-    };
-    println!("====================Overseerr====================");
-    println!("");
-    println!("====================Radarr====================");
-    let radarrs = match overseerr.get_radarrs(&conn) {
-        Ok(radarrs) => {
-            println!("Successfully got radarrs from overseerr");
-            radarrs
-        },
+    // Initialize the sqlite database
+    let conn = match database::open_database(&database_path) {
+        Ok(conn) => conn,
         Err(err) => {
-            println!("{:?}", err);
-            Vec::new()
+            println!("Could not open the database {} : {}", database_path, err);
+            std::process::exit(1);
         }
     };
 
-    for radarr in radarrs {
-        match radarr.populate_paths(&conn) {
-            Ok(_) => println!("Successfully populated paths for radarr"),
-            Err(err) => println!("{:?}", err)
-        }
-        match radarr.update_db_movies(&conn) {
+    match args.get(1).map(|arg| arg.as_str()) {
+        // protections are managed from the command line
+        Some("protect") => protect(&conn, &args[2..]),
+        Some("unprotect") => unprotect(&conn, &args[2..]),
+        Some("import-protections") => import_protections(&conn, &args[2..]),
+        // services are registered from the command line or the API
+        Some("service") => service(&conn, &args[2..]),
+        // keep running and follow the schedules
+        Some("daemon") => match daemon::run(&conn, &database_path) {
             Ok(_) => (),
             Err(err) => println!("{:?}", err)
-        };
-    }
-    println!("====================Radarr====================");
-    println!("");
-    println!("====================Sonarr====================");
-    let sonarrs = match overseerr.get_sonarrs() {
-        Ok(sonarrs) => {
-            println!("Successfully got sonarrs from overseerr");
-            sonarrs
         },
-        Err(err) => {
-            println!("{:?}", err);
-            Vec::new()
+        // only serve the API
+        Some("serve") => {
+            let address = std::env::var("API_ADDRESS").unwrap_or_else(|_| DEFAULT_API_ADDRESS.to_string());
+            match daemon::register_stop().and_then(|stop| api::serve(&address, &database_path, stop)) {
+                Ok(_) => (),
+                Err(err) => println!("{:?}", err)
+            }
+        },
+        // run every phase once
+        _ => match RunLock::acquire() {
+            Ok(_lock) => {
+                for phase in Phase::ALL {
+                    phase.run(&conn);
+                }
+            },
+            Err(err) => println!("{:?}", err)
         }
     };
+}
 
-    for sonarr in sonarrs {
-        match sonarr.populate_paths(&conn) {
-            Ok(_) => println!("Successfully populated paths for sonarr"),
-            Err(err) => println!("{:?}", err)
-        }
-        match sonarr.update_db_series(&conn) {
-            Ok(_) => (),
-            Err(err) => println!("{:?}", err)
-        };
-    }
-    println!("====================Sonarr====================");
-    println!("");
-    println!("====================Jellyfin====================");
-    for jellyfin in Jellyfin::get_all(&conn) {
-        match jellyfin.update_movies_activity(&conn) {
-            Ok(_) => (),
-            Err(err) => println!("{:?}", err)
-        };
-        match jellyfin.update_series_activity(&conn) {
-            Ok(_) => (),
-            Err(err) => println!("{:?}", err)
-        };
+// get the value following an option like "--reason"
+fn get_option(args: &[String], name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.get(index + 1).cloned()
+}
+
+// protect <movie|serie> <id|title> [--until YYYY-MM-DD] [--by NAME] [--reason TEXT]
+fn protect(conn: &Connection, args: &[String]) {
+    if args.len() < 2 {
+        println!("Usage : protect <movie|serie> <id|title> [--until YYYY-MM-DD] [--by NAME] [--reason TEXT]");
+        return;
     }
-    println!("====================Jellyfin====================");
-    println!("");
-    println!("====================Tautulli====================");
-    let tautullis = match Tautulli::get_all(&conn) {
-        Ok(tautullis) => {
-            println!("Successfully got tautullis from overseerr");
-            tautullis
-        },
+
+    // without a date the media is protected for ever
+    let until = match Protection::parse_until(&get_option(args, "--until").unwrap_or_default()) {
+        Ok(until) => until,
         Err(err) => {
-            println!("{:?}", err);
-            Vec::new()
+            println!("{}", err);
+            return;
         }
     };
-    for mut tautulli in tautullis {
-        match tautulli.update_medias_activity(&conn) {
-            Ok(_) => (),
-            Err(err) => println!("{:?}", err)
-        };
+    let protected_by = get_option(args, "--by").unwrap_or_default();
+    let reason = get_option(args, "--reason").unwrap_or_default();
+
+    let protection = Protection::new(&args[0], &args[1], until, &protected_by, &reason);
+    match protection.save(conn).and_then(|_| Protection::update_medias(conn)) {
+        Ok(_) => println!("Protected {} {}", args[0], args[1]),
+        Err(err) => println!("{:?}", err)
+    };
+}
+
+// unprotect <movie|serie> <id|title>
+fn unprotect(conn: &Connection, args: &[String]) {
+    if args.len() < 2 {
+        println!("Usage : unprotect <movie|serie> <id|title>");
+        return;
     }
-    println!("====================Tautulli====================");
-    println!("");
-    println!("====================Movies to delete====================");
-    // Get three months ago date as a timestamp
-    let three_months_ago = (chrono::Utc::now().timestamp() - 60 * 60 * 24 * 30 * 3) as i32;
-    let movies_to_delete = match Movie::get_movies_to_delete(&conn, three_months_ago) {
-        Ok(movies_to_delete) => {
-            println!("Successfully got movies to delete");
-            movies_to_delete
-        },
-        Err(err) => {
-            println!("{:?}", err);
-            Vec::new()
+
+    match Protection::delete(conn, &args[0], &args[1]).and_then(|quantity| Protection::update_medias(conn).map(|_| quantity)) {
+        Ok(quantity) => println!("Removed protections : {}", quantity),
+        Err(err) => println!("{:?}", err)
+    };
+}
+
+// import-protections <file.csv>
+fn import_protections(conn: &Connection, args: &[String]) {
+    let path = match args.first() {
+        Some(path) => path,
+        None => {
+            println!("Usage : import-protections <file.csv>");
+            return;
         }
     };
-    println!("Quantity founded : {:?}", movies_to_delete.len());
-    println!("====================Movies to delete====================");
-    println!("");
-    println!("====================Series to delete====================");
-    // Get three months ago date as a timestamp
-    let three_months_ago = (chrono::Utc::now().timestamp() - 60 * 60 * 24 * 30 * 3) as i32;
-    let series_to_delete = match Serie::get_series_to_delete(&conn, three_months_ago) {
-        Ok(series_to_delete) => {
-            println!("Successfully got series to delete");
-            series_to_delete
+
+    match Protection::import(conn, path).and_then(|quantity| Protection::update_medias(conn).map(|_| quantity)) {
+        Ok(quantity) => println!("Imported protections : {}", quantity),
+        Err(err) => println!("{:?}", err)
+    };
+}
+
+// service list
+// service add <kind> <url> <api_key> [--4k] [--activity-source SOURCE] [--force]
+// service update <kind> <id> [--url URL] [--api-key KEY] [--4k true|false] [--activity-source SOURCE] [--force]
+// service remove <kind> <id>
+// service test <kind> <id>
+fn service(conn: &Connection, args: &[String]) {
+    let usage = "Usage : service <list|add|update|remove|test> [kind] [id|url api_key] [options]";
+    let force = args.iter().any(|arg| arg == "--force");
+
+    let result: Result<()> = match args.first().map(|arg| arg.as_str()) {
+        Some("list") => list_services(conn),
+        Some("add") if args.len() >= 4 => {
+            let mut config = ServiceConfig::new(&args[1], &args[2], &args[3], args.iter().any(|arg| arg == "--4k"));
+            if let Some(activity_source) = get_option(args, "--activity-source") {
+                config.activity_source = activity_source;
+            }
+            save_service(conn, &mut config, force)
         },
-        Err(err) => {
-            println!("{:?}", err);
-            Vec::new()
-        }
+        Some("update") if args.len() >= 3 => args[2].parse::<i32>().map_err(Error::from)
+            .and_then(|id| ServiceConfig::get_by_id(conn, &args[1], id))
+            .and_then(|config| config.update(get_option(args, "--url"), get_option(args, "--api-key"), get_option(args, "--4k").map(|is4k| is4k == "true"), get_option(args, "--activity-source")))
+            .and_then(|mut updated| save_service(conn, &mut updated, force)),
+        Some("remove") if args.len() >= 3 => args[2].parse::<i32>().map_err(Error::from)
+            .and_then(|id| ServiceConfig::get_by_id(conn, &args[1], id))
+            .and_then(|config| config.delete(conn))
+            .map(|_| println!("Removed {} {}", args[1], args[2])),
+        Some("test") if args.len() >= 3 => args[2].parse::<i32>().map_err(Error::from)
+            .and_then(|id| ServiceConfig::get_by_id(conn, &args[1], id))
+            .map(|config| print_connection_test(&config)),
+        _ => Err(Error::Invalid(usage.to_string()))
     };
-    println!("Quantity founded : {:?}", series_to_delete.len());
-    println!("====================Series to delete====================");
+
+    match result {
+        Ok(_) => (),
+        Err(err) => println!("{}", err)
+    };
+}
+
+fn list_services(conn: &Connection) -> Result<()> {
+    for kind in SERVICE_KINDS {
+        for config in ServiceConfig::get_all(conn, kind)? {
+            let activity_source = if config.activity_source.is_empty() { String::new() } else { format!(" (activity from {})", config.activity_source) };
+            println!("{} {} : {}{}{}", config.kind, config.id, config.url, if config.is4k { " (4K)" } else { "" }, activity_source);
+        }
+    }
+    Ok(())
+}
+
+// test the connection before saving, unless forced a failed test is not saved
+fn save_service(conn: &Connection, config: &mut ServiceConfig, force: bool) -> Result<()> {
+    let test = config.save_tested(conn, force)?;
+    println!("Reachable : {}, authenticated : {}, version : {}", test.reachable, test.authenticated, test.version);
+    if !test.is_success() && !force {
+        return Err(Error::Invalid(format!("Not saved, {} (use --force to save anyway)", test.error)));
+    }
+    println!("Saved {} {}", config.kind, config.id);
+    Ok(())
+}
+
+fn print_connection_test(config: &ServiceConfig) {
+    let test = config.test();
+    println!("Reachable : {}, authenticated : {}, version : {}", test.reachable, test.authenticated, test.version);
+    if !test.error.is_empty() {
+        println!("{}", test.error);
+    }
 }
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+
+use crate::pipeline::Pipeline;
+use crate::report::Report;
+use crate::services::disk::Disk;
+use crate::services::movie::Movie;
+use crate::services::protection::Protection;
+use crate::services::rule::Rule;
+use crate::services::serie::Serie;
+
+// a step of a pass, every phase can be scheduled on its own in daemon mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Overseerr,
+    Radarr,
+    Sonarr,
+    Jellyfin,
+    Tautulli,
+    Deletion
+}
+
+impl Phase {
+    // every phase, in the order of a full pass
+    pub const ALL: [Phase; 6] = [Phase::Overseerr, Phase::Radarr, Phase::Sonarr, Phase::Jellyfin, Phase::Tautulli, Phase::Deletion];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            Phase::Overseerr => "overseerr",
+            Phase::Radarr => "radarr",
+            Phase::Sonarr => "sonarr",
+            Phase::Jellyfin => "jellyfin",
+            Phase::Tautulli => "tautulli",
+            Phase::Deletion => "deletion"
+        }
+    }
+
+    pub fn run(&self, conn: &Connection) {
+        // the backends are loaded for each phase, to use the instances discovered by the previous ones
+        match self {
+            Phase::Overseerr => sync(conn, "Overseerr/Jellyseerr", |pipeline| pipeline.sync_request_managers(conn)),
+            Phase::Radarr => sync(conn, "Radarr", |pipeline| pipeline.sync_media_managers(conn, Some("radarr"))),
+            Phase::Sonarr => sync(conn, "Sonarr", |pipeline| pipeline.sync_media_managers(conn, Some("sonarr"))),
+            Phase::Jellyfin => sync(conn, "Jellyfin", |pipeline| pipeline.sync_activity_sources(conn, Some("jellyfin"))),
+            Phase::Tautulli => sync(conn, "Tautulli", |pipeline| pipeline.sync_activity_sources(conn, Some("tautulli"))),
+            Phase::Deletion => delete(conn)
+        }
+        println!();
+    }
+}
+
+// sync the backends registered in the database
+fn sync(conn: &Connection, title: &str, run: impl FnOnce(&mut Pipeline)) {
+    println!("===================={}====================", title);
+    run(&mut Pipeline::from_db(conn));
+    println!("===================={}====================", title);
+}
+
+// delete the medias selected by the rules or by the disk pressure
+fn delete(conn: &Connection) {
+    match Protection::update_medias(conn) {
+        Ok(_) => println!("Successfully updated protected medias"),
+        Err(err) => println!("{:?}", err)
+    };
+    let add_import_exclusion = std::env::var("RADARR_ADD_IMPORT_EXCLUSION").unwrap_or_default() == "true";
+    let dry_run = std::env::var("DRY_RUN").unwrap_or_default() == "true";
+    match std::env::var("DELETION_MODE").unwrap_or_default().as_str() {
+        "pressure" => delete_by_pressure(conn, add_import_exclusion),
+        _ => delete_by_rules(conn, add_import_exclusion, dry_run)
+    };
+}
+
+// delete the medias matching the configured rules, or only report them on dry run
+fn delete_by_rules(conn: &Connection, add_import_exclusion: bool, dry_run: bool) {
+    println!("====================Rules====================");
+    let rules = match Rule::get_all(conn) {
+        Ok(rules) => rules,
+        Err(err) => {
+            println!("{:?}", err);
+            Vec::new()
+        }
+    };
+    for rule in &rules {
+        println!("Rule : {}", rule.name);
+    }
+    println!("====================Rules====================");
+    println!();
+    let movies_to_delete = match Movie::get_movies_to_delete(conn, &rules) {
+        Ok(movies_to_delete) => {
+            println!("Successfully got movies to delete");
+            movies_to_delete
+        },
+        Err(err) => {
+            println!("{:?}", err);
+            Vec::new()
+        }
+    };
+    let series_to_delete = match Serie::get_series_to_delete(conn, &rules) {
+        Ok(series_to_delete) => {
+            println!("Successfully got series to delete");
+            series_to_delete
+        },
+        Err(err) => {
+            println!("{:?}", err);
+            Vec::new()
+        }
+    };
+
+    if dry_run {
+        println!("====================Dry run====================");
+        let report = Report::new(conn, &rules, &movies_to_delete, &series_to_delete);
+        let path = std::env::var("REPORT_PATH").unwrap_or_else(|_| "report.json".to_string());
+        match report.save(&path) {
+            Ok(_) => println!("Report saved : {}", path),
+            Err(err) => println!("{:?}", err)
+        };
+        for disk in report.disks {
+            println!("Reclaimable space on disk {} : {}", disk.disk, disk.reclaimed);
+        }
+        println!("====================Dry run====================");
+        return;
+    }
+
+    // space freed by the deletions on each disk
+    let mut freed_spaces: HashMap<String, i64> = HashMap::new();
+    println!("====================Movies to delete====================");
+    println!("Quantity founded : {:?}", movies_to_delete.len());
+    let mut quantity_deleted = 0;
+    for (mut movie, is4k) in movies_to_delete {
+        let disk = movie.get_disk(conn, is4k);
+        let size = if is4k { movie.size_4k } else { movie.size_hd };
+        match movie.delete_copy(conn, is4k, add_import_exclusion) {
+            Ok(_) => {
+                quantity_deleted += 1;
+                if let Ok(disk) = disk {
+                    *freed_spaces.entry(disk.get_name().to_string()).or_insert(0) += size;
+                }
+            },
+            Err(err) => println!("{:?}", err)
+        };
+    }
+    println!("Deleted movies : {}", quantity_deleted);
+    println!("====================Movies to delete====================");
+    println!();
+    println!("====================Series to delete====================");
+    println!("Quantity founded : {:?}", series_to_delete.len());
+    let mut quantity_deleted = 0;
+    for (mut serie, is4k) in series_to_delete {
+        let disk = serie.get_disk(conn, is4k);
+        let size = if is4k { serie.size_4k } else { serie.size_hd };
+        match serie.delete_copy(conn, is4k) {
+            Ok(_) => {
+                quantity_deleted += 1;
+                if let Ok(disk) = disk {
+                    *freed_spaces.entry(disk.get_name().to_string()).or_insert(0) += size;
+                }
+            },
+            Err(err) => println!("{:?}", err)
+        };
+    }
+    println!("Deleted series : {}", quantity_deleted);
+    println!("====================Series to delete====================");
+    println!();
+    for (disk, freed_space) in freed_spaces {
+        println!("Freed space on disk {} : {}", disk, freed_space);
+    }
+}
+
+// delete the least recently viewed medias of the disks whose free space crossed their high watermark
+fn delete_by_pressure(conn: &Connection, add_import_exclusion: bool) {
+    println!("====================Disk pressure====================");
+    let disks = match Disk::get_all(conn) {
+        Ok(disks) => disks,
+        Err(err) => {
+            println!("{:?}", err);
+            Vec::new()
+        }
+    };
+    let age_floor = match Rule::get_all(conn) {
+        Ok(rules) => Rule::age_floor(&rules),
+        Err(err) => {
+            println!("{:?}", err);
+            return;
+        }
+    };
+    for disk in disks {
+        println!("Disk {} : {} free on {}", disk.get_name(), disk.get_free_space(), disk.get_total_space());
+        match disk.get_medias_size(conn) {
+            Ok(medias_size) => println!("Medias on disk {} : {}", disk.get_name(), medias_size),
+            Err(err) => println!("{:?}", err)
+        };
+        if let Err(err) = disk.check_watermarks() {
+            println!("{}", err);
+            continue;
+        }
+        if !disk.is_under_pressure() {
+            continue;
+        }
+        match disk.relieve_pressure(conn, &age_floor, add_import_exclusion) {
+            Ok(freed_space) => println!("Freed space on disk {} : {}", disk.get_name(), freed_space),
+            Err(err) => println!("{:?}", err)
+        };
+    }
+    println!("====================Disk pressure====================");
+}
@@ -0,0 +1,215 @@
+use rusqlite::Connection;
+
+use crate::services::backend::{ActivitySource, MediaManager, RequestManager};
+use crate::services::jellyfin::Jellyfin;
+use crate::services::jellyseerr::Jellyseerr;
+use crate::services::overseerr::Overseerr;
+use crate::services::radarr::Radarr;
+use crate::services::sonarr::Sonarr;
+use crate::services::tautulli::Tautulli;
+
+// the registered backends, synchronised in the order request managers, media managers and activity sources
+#[derive(Default)]
+pub struct Pipeline {
+    request_managers: Vec<Box<dyn RequestManager>>,
+    media_managers: Vec<Box<dyn MediaManager>>,
+    activity_sources: Vec<Box<dyn ActivitySource>>
+}
+
+// log the error of a backend and keep going with the next one
+fn report<T>(name: &str, result: crate::error::Result<T>) {
+    match result {
+        Ok(_) => (),
+        Err(err) => println!("{} : {}", name, err)
+    };
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    // register every service instance of the database
+    pub fn from_db(conn: &Connection) -> Self {
+        let mut pipeline = Pipeline::new();
+
+        match Overseerr::get_all(conn) {
+            Ok(overseerrs) => overseerrs.into_iter().for_each(|overseerr| pipeline.register_request_manager(Box::new(overseerr))),
+            Err(err) => println!("{:?}", err)
+        };
+        match Jellyseerr::get_all(conn) {
+            Ok(jellyseerrs) => jellyseerrs.into_iter().for_each(|jellyseerr| pipeline.register_request_manager(Box::new(jellyseerr))),
+            Err(err) => println!("{:?}", err)
+        };
+        match Radarr::get_all(conn) {
+            Ok(radarrs) => radarrs.into_iter().for_each(|radarr| pipeline.register_media_manager(Box::new(radarr))),
+            Err(err) => println!("{:?}", err)
+        };
+        match Sonarr::get_all(conn) {
+            Ok(sonarrs) => sonarrs.into_iter().for_each(|sonarr| pipeline.register_media_manager(Box::new(sonarr))),
+            Err(err) => println!("{:?}", err)
+        };
+        match Jellyfin::get_all(conn) {
+            Ok(jellyfins) => jellyfins.into_iter().for_each(|jellyfin| pipeline.register_activity_source(Box::new(jellyfin))),
+            Err(err) => println!("{:?}", err)
+        };
+        match Tautulli::get_all(conn) {
+            Ok(tautullis) => tautullis.into_iter().for_each(|tautulli| pipeline.register_activity_source(Box::new(tautulli))),
+            Err(err) => println!("{:?}", err)
+        };
+
+        pipeline
+    }
+
+    pub fn register_request_manager(&mut self, request_manager: Box<dyn RequestManager>) {
+        self.request_managers.push(request_manager);
+    }
+
+    pub fn register_media_manager(&mut self, media_manager: Box<dyn MediaManager>) {
+        self.media_managers.push(media_manager);
+    }
+
+    pub fn register_activity_source(&mut self, activity_source: Box<dyn ActivitySource>) {
+        self.activity_sources.push(activity_source);
+    }
+
+    // seed the medias and discover the media managers from every request manager
+    pub fn sync_request_managers(&self, conn: &Connection) {
+        for request_manager in &self.request_managers {
+            let name = request_manager.get_name();
+            report(name, request_manager.update_db_movies(conn));
+            report(name, request_manager.update_db_series(conn));
+            report(name, request_manager.update_db_items(conn));
+            match request_manager.discover_media_managers(conn) {
+                Ok(_) => println!("Successfully got radarrs and sonarrs from {}", name),
+                Err(err) => println!("{} : {}", name, err)
+            };
+        }
+    }
+
+    // sync the media managers, only those named so when a name is given
+    pub fn sync_media_managers(&self, conn: &Connection, name: Option<&str>) {
+        for media_manager in self.media_managers.iter().filter(|media_manager| name.is_none_or(|name| media_manager.get_name() == name)) {
+            let name = media_manager.get_name();
+            match media_manager.populate_paths(conn) {
+                Ok(_) => println!("Successfully populated paths for {}", name),
+                Err(err) => println!("{} : {}", name, err)
+            };
+            report(name, media_manager.update_db_medias(conn));
+        }
+    }
+
+    // sync the activity sources, only those named so when a name is given
+    pub fn sync_activity_sources(&mut self, conn: &Connection, name: Option<&str>) {
+        for activity_source in self.activity_sources.iter_mut().filter(|activity_source| name.is_none_or(|name| activity_source.get_name() == name)) {
+            let result = activity_source.update_activity(conn);
+            report(activity_source.get_name(), result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::error::Result;
+
+    // a backend of any kind, recording the calls made to it
+    struct FakeBackend {
+        name: &'static str,
+        calls: Rc<RefCell<Vec<String>>>
+    }
+
+    impl FakeBackend {
+        fn new(name: &'static str, calls: &Rc<RefCell<Vec<String>>>) -> Box<Self> {
+            Box::new(FakeBackend { name, calls: Rc::clone(calls) })
+        }
+
+        fn record(&self, call: &str) -> Result<()> {
+            self.calls.borrow_mut().push(format!("{} {}", self.name, call));
+            Ok(())
+        }
+    }
+
+    impl RequestManager for FakeBackend {
+        fn get_name(&self) -> &str {
+            self.name
+        }
+
+        fn update_db_movies(&self, _conn: &Connection) -> Result<()> {
+            self.record("movies")
+        }
+
+        fn update_db_series(&self, _conn: &Connection) -> Result<()> {
+            self.record("series")
+        }
+
+        fn update_db_items(&self, _conn: &Connection) -> Result<()> {
+            self.record("items")
+        }
+
+        fn discover_media_managers(&self, _conn: &Connection) -> Result<()> {
+            self.record("discover")
+        }
+    }
+
+    impl MediaManager for FakeBackend {
+        fn get_name(&self) -> &str {
+            self.name
+        }
+
+        fn populate_paths(&self, _conn: &Connection) -> Result<()> {
+            self.record("paths")
+        }
+
+        fn update_db_medias(&self, _conn: &Connection) -> Result<()> {
+            self.record("medias")
+        }
+    }
+
+    impl ActivitySource for FakeBackend {
+        fn get_name(&self) -> &str {
+            self.name
+        }
+
+        fn update_activity(&mut self, _conn: &Connection) -> Result<()> {
+            self.record("activity")
+        }
+    }
+
+    #[test]
+    fn phases_run_in_order_on_the_named_backends() {
+        let conn = Connection::open_in_memory().unwrap();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+
+        let mut pipeline = Pipeline::new();
+        pipeline.register_activity_source(FakeBackend::new("tautulli", &calls));
+        pipeline.register_activity_source(FakeBackend::new("jellyfin", &calls));
+        pipeline.register_media_manager(FakeBackend::new("sonarr", &calls));
+        pipeline.register_media_manager(FakeBackend::new("radarr", &calls));
+        pipeline.register_request_manager(FakeBackend::new("overseerr", &calls));
+
+        // the order of the phases of a pass
+        pipeline.sync_request_managers(&conn);
+        pipeline.sync_media_managers(&conn, Some("radarr"));
+        pipeline.sync_media_managers(&conn, Some("sonarr"));
+        pipeline.sync_activity_sources(&conn, Some("jellyfin"));
+        pipeline.sync_activity_sources(&conn, Some("tautulli"));
+        assert_eq!(*calls.borrow(), [
+            "overseerr movies", "overseerr series", "overseerr items", "overseerr discover",
+            "radarr paths", "radarr medias",
+            "sonarr paths", "sonarr medias",
+            "jellyfin activity",
+            "tautulli activity"
+        ]);
+
+        // without a name every backend of the kind is synced, in registration order
+        calls.borrow_mut().clear();
+        pipeline.sync_media_managers(&conn, None);
+        pipeline.sync_activity_sources(&conn, None);
+        pipeline.sync_activity_sources(&conn, Some("plex"));
+        assert_eq!(*calls.borrow(), ["sonarr paths", "sonarr medias", "radarr paths", "radarr medias", "tautulli activity", "jellyfin activity"]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::services::movie::Movie;
+use crate::services::radarr::Radarr;
+use crate::services::rule::Rule;
+use crate::services::serie::Serie;
+use crate::services::sonarr::Sonarr;
+
+// a copy of a media that would be deleted, with the reasons of its selection
+#[derive(Serialize)]
+pub struct ReportItem {
+    pub media_type: String,
+    pub media_id: i32,
+    pub title: String,
+    pub quality: String,
+    pub instance: String,
+    pub path: String,
+    pub disk: String,
+    pub size: i64,
+    pub last_view: i32,
+    pub last_view_source: String,
+    // the names of every rule matching the copy
+    pub rule: String
+}
+
+#[derive(Serialize)]
+pub struct DiskReport {
+    pub disk: String,
+    pub reclaimed: i64
+}
+
+// dry-run report of the medias that would be deleted by the rules
+#[derive(Serialize)]
+pub struct Report {
+    pub items: Vec<ReportItem>,
+    pub disks: Vec<DiskReport>
+}
+
+impl Report {
+    // one item per selected copy, the boolean of a media telling if the copy is the 4K one
+    pub fn new(conn: &Connection, rules: &[Rule], movies: &[(Movie, bool)], series: &[(Serie, bool)]) -> Self {
+        let mut items = Vec::new();
+
+        for (movie, is4k) in movies {
+            let is4k = *is4k;
+            let path = if is4k { &movie.path_4k } else { &movie.path_hd };
+            let rules: Vec<&str> = rules.iter().filter(|rule| rule.matches_movie(movie, is4k)).map(|rule| rule.name.as_str()).collect();
+
+            items.push(ReportItem {
+                media_type: "movie".to_string(),
+                media_id: movie.tmdb_id,
+                title: movie.name.clone(),
+                quality: if is4k { "4k".to_string() } else { "hd".to_string() },
+                instance: Radarr::get_by_path(conn, path, is4k).map(|radarr| radarr.get_url().to_string()).unwrap_or_default(),
+                path: path.clone(),
+                disk: movie.get_disk(conn, is4k).map(|disk| disk.get_name().to_string()).unwrap_or_default(),
+                size: if is4k { movie.size_4k } else { movie.size_hd },
+                last_view: movie.last_view,
+                last_view_source: movie.last_view_source.clone(),
+                rule: rules.join(", ")
+            });
+        }
+
+        for (serie, is4k) in series {
+            let is4k = *is4k;
+            let path = if is4k { &serie.path_4k } else { &serie.path_hd };
+            let rules: Vec<&str> = rules.iter().filter(|rule| rule.matches_serie(serie, is4k)).map(|rule| rule.name.as_str()).collect();
+
+            items.push(ReportItem {
+                media_type: "serie".to_string(),
+                media_id: serie.tvdb_id,
+                title: serie.name.clone(),
+                quality: if is4k { "4k".to_string() } else { "hd".to_string() },
+                instance: Sonarr::get_by_path(conn, path, is4k).map(|sonarr| sonarr.get_url().to_string()).unwrap_or_default(),
+                path: path.clone(),
+                disk: serie.get_disk(conn, is4k).map(|disk| disk.get_name().to_string()).unwrap_or_default(),
+                size: if is4k { serie.size_4k } else { serie.size_hd },
+                last_view: serie.last_view,
+                last_view_source: serie.last_view_source.clone(),
+                rule: rules.join(", ")
+            });
+        }
+
+        // bytes reclaimed on each disk
+        let mut reclaimed: BTreeMap<String, i64> = BTreeMap::new();
+        for item in &items {
+            *reclaimed.entry(item.disk.clone()).or_insert(0) += item.size;
+        }
+        let disks = reclaimed.into_iter().map(|(disk, reclaimed)| DiskReport { disk, reclaimed }).collect();
+
+        Report { items, disks }
+    }
+
+    pub fn to_json(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    // one line per media copy, followed after an empty line by the space reclaimed on each disk
+    pub fn to_csv(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(Vec::new());
+        for item in &self.items {
+            writer.serialize(item)?;
+        }
+
+        writer.write_record([""])?;
+        writer.write_record(["disk", "reclaimed"])?;
+        for disk in &self.disks {
+            writer.write_record([disk.disk.as_str(), disk.reclaimed.to_string().as_str()])?;
+        }
+
+        Ok(String::from_utf8(writer.into_inner()?)?)
+    }
+
+    // write the report as CSV if the file ends with ".csv", as JSON otherwise
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let content = if path.ends_with(".csv") { self.to_csv()? } else { self.to_json()? };
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(media_id: i32, quality: &str, disk: &str, size: i64, rule: &str) -> ReportItem {
+        ReportItem {
+            media_type: "movie".to_string(),
+            media_id,
+            title: format!("Movie {}", media_id),
+            quality: quality.to_string(),
+            instance: "http://radarr:7878".to_string(),
+            path: "/movies".to_string(),
+            disk: disk.to_string(),
+            size,
+            last_view: 1700000000,
+            last_view_source: "tautulli".to_string(),
+            rule: rule.to_string()
+        }
+    }
+
+    #[test]
+    fn csv_ends_with_the_space_reclaimed_on_each_disk() {
+        let report = Report {
+            items: vec![item(1, "hd", "media", 2, "Old, Large"), item(2, "4k", "media4k", 8, "Large")],
+            disks: vec![
+                DiskReport { disk: "media".to_string(), reclaimed: 2 },
+                DiskReport { disk: "media4k".to_string(), reclaimed: 8 }
+            ]
+        };
+
+        let csv = report.to_csv().unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "media_type,media_id,title,quality,instance,path,disk,size,last_view,last_view_source,rule");
+        assert_eq!(lines[1], "movie,1,Movie 1,hd,http://radarr:7878,/movies,media,2,1700000000,tautulli,\"Old, Large\"");
+        assert_eq!(&lines[4..], ["disk,reclaimed", "media,2", "media4k,8"]);
+    }
+}
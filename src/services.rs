@@ -1,10 +1,20 @@
+pub mod backend;
+pub mod deletion;
 pub mod jellyfin;
+pub mod jellyfin_item;
 pub mod jellyseerr;
 pub mod overseerr;
+pub mod plex_item;
+pub mod protection;
 pub mod radarr;
+pub mod seerr;
 pub mod sonarr;
 pub mod tautulli;
-mod disk;
+pub mod disk;
+pub mod http_client;
+mod media;
 mod path;
 pub mod movie;
-pub mod serie;
\ No newline at end of file
+pub mod rule;
+pub mod serie;
+pub mod service_config;
\ No newline at end of file
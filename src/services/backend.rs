@@ -0,0 +1,41 @@
+use rusqlite::Connection;
+
+use crate::error::Result;
+
+// a service where medias are requested, like overseerr or jellyseerr
+pub trait RequestManager {
+    fn get_name(&self) -> &str;
+
+    // add the requested movies missing from the database
+    fn update_db_movies(&self, conn: &Connection) -> Result<()>;
+
+    // add the requested series missing from the database
+    fn update_db_series(&self, conn: &Connection) -> Result<()>;
+
+    // link the medias to the items of the media server, when the request manager knows them
+    fn update_db_items(&self, _conn: &Connection) -> Result<()> {
+        Ok(())
+    }
+
+    // register the media managers configured in the request manager
+    fn discover_media_managers(&self, conn: &Connection) -> Result<()>;
+}
+
+// a service downloading and storing medias, like radarr or sonarr
+pub trait MediaManager {
+    fn get_name(&self) -> &str;
+
+    // save the root folders and their disks
+    fn populate_paths(&self, conn: &Connection) -> Result<()>;
+
+    // add or update the medias of the media manager in the database
+    fn update_db_medias(&self, conn: &Connection) -> Result<()>;
+}
+
+// a service knowing when medias were viewed, like tautulli or jellyfin
+pub trait ActivitySource {
+    fn get_name(&self) -> &str;
+
+    // update the last view of the medias
+    fn update_activity(&mut self, conn: &Connection) -> Result<()>;
+}
@@ -0,0 +1,51 @@
+use rusqlite::{Connection, params};
+
+use crate::error::Result;
+
+// outcome of a deletion request made to a radarr or sonarr instance
+#[derive(Debug)]
+pub struct Deletion {
+    pub media_type: String,
+    pub media_id: i32,
+    pub name: String,
+    pub service_id: i32,
+    pub path: String,
+    pub date: i32,
+    pub success: bool,
+    pub error: String
+}
+
+impl Deletion {
+    pub fn new(media_type: &str, media_id: i32, name: &str, service_id: i32, path: &str, result: &Result<()>) -> Self {
+        Deletion {
+            media_type: media_type.to_string(),
+            media_id,
+            name: name.to_string(),
+            service_id,
+            path: path.to_string(),
+            date: chrono::Utc::now().timestamp() as i32,
+            success: result.is_ok(),
+            error: match result {
+                Ok(_) => String::new(),
+                Err(err) => err.to_string()
+            }
+        }
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        conn.execute("
+            INSERT INTO deletion (media_type, media_id, name, service_id, path, date, success, error)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ", params![
+            &self.media_type,
+            &self.media_id,
+            &self.name,
+            &self.service_id,
+            &self.path,
+            &self.date,
+            &self.success,
+            &self.error,
+        ])?;
+        Ok(())
+    }
+}
@@ -1,65 +1,436 @@
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, Row, params};
+use serde::{Deserialize, Serialize};
 
+use super::{movie::Movie, rule::Rule, serie::Serie};
+
+use crate::error::{Error, Result};
+
+// a mount point as returned by radarr and sonarr "/api/v3/diskspace"
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskSpace {
+    path: String,
+    #[serde(default)]
+    label: String,
+    free_space: u64,
+    total_space: u64
+}
+
+#[derive(Serialize)]
 pub struct Disk {
     id: i32,
-    free_space: u64
+    path: String,
+    label: String,
+    total_space: u64,
+    // free space of the latest sample
+    free_space: u64,
+    // deletion starts when the free space goes under the high watermark
+    high_watermark: u64,
+    // and stops once the projected free space reaches the low watermark
+    low_watermark: u64
 }
 
-impl Disk {
-    pub fn new(conn: &Connection, free_space: u64) -> Self {
-        conn.execute("INSERT INTO disk (free_space) VALUES (?)", [free_space]).unwrap();
+impl DiskSpace {
+    // stable identity of the disk, the volume label if any or the mount path, with the total space
+    fn get_key(&self) -> String {
+        if self.label.is_empty() {
+            format!("{}:{}", self.path, self.total_space)
+        } else {
+            format!("{}:{}", self.label, self.total_space)
+        }
+    }
 
-        Disk {
-            id: i32::from(conn.last_insert_rowid() as i32),
-            free_space: free_space
+    // the deepest mount point containing a root folder
+    fn find<'a>(disk_spaces: &'a [DiskSpace], path: &str) -> Option<&'a DiskSpace> {
+        disk_spaces.iter()
+            .filter(|disk_space| disk_space.contains(path))
+            .max_by_key(|disk_space| disk_space.path.len())
+    }
+
+    // check if a root folder is stored on this mount point, a mount point without path contains nothing
+    fn contains(&self, path: &str) -> bool {
+        if self.path.is_empty() {
+            return false;
         }
+        // trimming the separators, the root mount point "/" contains every absolute path
+        let mount = self.path.trim_end_matches(['/', '\\']);
+        path == self.path || path == mount || path.starts_with(&format!("{}/", mount)) || path.starts_with(&format!("{}\\", mount))
+    }
+}
+
+impl Disk {
+    fn from_row(row: &Row) -> rusqlite::Result<Disk> {
+        Ok(Disk {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            label: row.get(2)?,
+            total_space: row.get(3)?,
+            free_space: row.get(4)?,
+            high_watermark: row.get(5)?,
+            low_watermark: row.get(6)?
+        })
     }
 
     pub fn get_id(&self) -> i32 {
         self.id
     }
 
-    pub fn get_by_id(id: i32) -> Result<Disk, Box<dyn std::error::Error>> {
-        let conn: Connection = Connection::open("data.db")?;
-
-        let mut stmt = conn.prepare("SELECT id, free_space FROM disk WHERE id = ?")?;
-        let mut disk_iter = stmt.query_map([id], |row| {
-            Ok(Disk {
-                id: row.get(0)?,
-                free_space: row.get(0)?
-            })
-        })?;
+    pub fn get_name(&self) -> &str {
+        if self.label.is_empty() { &self.path } else { &self.label }
+    }
 
-        if let Some(result) = disk_iter.next() {
-            result.map_err(|err| err.into())
-        } else {
-            Err("Disk not found".into())
-        }
+    pub fn get_total_space(&self) -> u64 {
+        self.total_space
     }
 
     pub fn get_free_space(&self) -> u64 {
         self.free_space
     }
 
-    pub fn set_free_space(&mut self, free_space: u64) {
+    // get the disk holding a root folder and record his free space, none if no mount point contains the root folder
+    pub fn from_root_folder(conn: &Connection, path: &str, disk_spaces: &[DiskSpace]) -> Result<Option<Disk>> {
+        let disk_space = match DiskSpace::find(disk_spaces, path) {
+            Some(disk_space) => disk_space,
+            None => return Ok(None)
+        };
+
+        let key = disk_space.get_key();
+        conn.execute("
+            INSERT INTO disk (key, path, label, total_space)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(key) DO UPDATE SET path = ?2, label = ?3
+        ", params![key, disk_space.path, disk_space.label, disk_space.total_space])?;
+
+        let mut disk = Disk::get_by_key(conn, &key)?;
+        disk.add_sample(conn, disk_space.free_space)?;
+
+        Ok(Some(disk))
+    }
+
+    // store a time-stamped free space sample
+    pub fn add_sample(&mut self, conn: &Connection, free_space: u64) -> Result<()> {
+        let date = chrono::Utc::now().timestamp() as i32;
+        conn.execute("INSERT INTO disk_space (disk_id, date, free_space) VALUES (?, ?, ?)", params![self.id, date, free_space])?;
         self.free_space = free_space;
+        Ok(())
+    }
+
+    pub fn get_by_key(conn: &Connection, key: &str) -> Result<Disk> {
+        let mut stmt = conn.prepare("
+            SELECT id, path, label, total_space,
+                IFNULL((SELECT free_space FROM disk_space WHERE disk_id = disk.id ORDER BY id DESC LIMIT 1), 0),
+                high_watermark, low_watermark
+            FROM disk
+            WHERE key = ?
+        ")?;
+        let mut disk_iter = stmt.query_map([key], Disk::from_row)?;
+
+        if let Some(result) = disk_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Disk {}", key)))
+        }
     }
 
-    pub fn get_by_free_space(free_space: u64) -> Result<Disk, Box<dyn std::error::Error>> {
-        let conn: Connection = Connection::open("data.db")?;
+    pub fn get_by_id(conn: &Connection, id: i32) -> Result<Disk> {
+        let mut stmt = conn.prepare("
+            SELECT id, path, label, total_space,
+                IFNULL((SELECT free_space FROM disk_space WHERE disk_id = disk.id ORDER BY id DESC LIMIT 1), 0),
+                high_watermark, low_watermark
+            FROM disk
+            WHERE id = ?
+        ")?;
+        let mut disk_iter = stmt.query_map([id], Disk::from_row)?;
 
-        let mut stmt = conn.prepare("SELECT id, free_space FROM disk WHERE free_space = ?")?;
-        let mut disk_iter = stmt.query_map([free_space], |row| {
-            Ok(Disk {
-                id: row.get(0)?,
-                free_space: row.get(1)?
-            })
-        })?;
-        
         if let Some(result) = disk_iter.next() {
             result.map_err(|err| err.into())
         } else {
-            Err("Disk not found".into())
+            Err(Error::NotFound("Disk".to_string()))
+        }
+    }
+
+    pub fn get_all(conn: &Connection) -> Result<Vec<Disk>> {
+        let mut stmt = conn.prepare("
+            SELECT id, path, label, total_space,
+                IFNULL((SELECT free_space FROM disk_space WHERE disk_id = disk.id ORDER BY id DESC LIMIT 1), 0),
+                high_watermark, low_watermark
+            FROM disk
+        ")?;
+        let disk_iter = stmt.query_map([], Disk::from_row)?;
+
+        let mut disks = Vec::new();
+        for disk in disk_iter {
+            disks.push(disk?);
+        }
+
+        Ok(disks)
+    }
+
+    // the deletion stops above the free space it starts under, so the low watermark must be the greater one
+    pub fn check_watermarks(&self) -> Result<()> {
+        if self.high_watermark > 0 && self.low_watermark <= self.high_watermark {
+            return Err(Error::Invalid(format!("Disk {} : the low watermark {} must be greater than the high watermark {}", self.get_name(), self.low_watermark, self.high_watermark)));
+        }
+        Ok(())
+    }
+
+    // get the space that would be freed by deleting every media stored on the disk
+    pub fn get_medias_size(&self, conn: &Connection) -> Result<i64> {
+        Ok(Movie::get_size_on_disk(conn, self.id)? + Serie::get_size_on_disk(conn, self.id)?)
+    }
+
+    // a disk without watermarks is never under pressure
+    pub fn is_under_pressure(&self) -> bool {
+        self.high_watermark > 0 && self.free_space < self.high_watermark
+    }
+
+    // delete the least recently viewed medias of the disk until the low watermark is reached,
+    // medias younger than the age floor of the rules are kept
+    pub fn relieve_pressure(&self, conn: &Connection, age_floor: &Rule, add_import_exclusion: bool) -> Result<u64> {
+        let medias = select_medias(Movie::get_on_disk(conn, self.id)?, Serie::get_on_disk(conn, self.id)?, age_floor);
+        Ok(self.delete_until_low_watermark(medias, |media| media.delete(conn, add_import_exclusion)))
+    }
+
+    // delete the medias in order until the projected free space reaches the low watermark, a failed deletion frees nothing
+    fn delete_until_low_watermark(&self, medias: Vec<DiskMedia>, mut delete: impl FnMut(&mut DiskMedia) -> Result<()>) -> u64 {
+        let mut projected_free_space = self.free_space;
+
+        for mut media in medias {
+            if projected_free_space >= self.low_watermark {
+                break;
+            }
+            match delete(&mut media) {
+                Ok(_) => projected_free_space += media.get_size().max(0) as u64,
+                Err(err) => println!("{:?}", err)
+            }
         }
+
+        projected_free_space.saturating_sub(self.free_space)
     }
-}
\ No newline at end of file
+}
+
+// the HD or 4K copy of a movie or a serie stored on a disk, true being the 4K one
+enum DiskMedia {
+    Movie(Movie, bool),
+    Serie(Serie, bool)
+}
+
+impl DiskMedia {
+    fn get_last_view(&self) -> i32 {
+        match self {
+            DiskMedia::Movie(movie, _) => movie.last_view,
+            DiskMedia::Serie(serie, _) => serie.last_view
+        }
+    }
+
+    fn get_size(&self) -> i64 {
+        match self {
+            DiskMedia::Movie(movie, is4k) => if *is4k { movie.size_4k } else { movie.size_hd },
+            DiskMedia::Serie(serie, is4k) => if *is4k { serie.size_4k } else { serie.size_hd }
+        }
+    }
+
+    fn delete(&mut self, conn: &Connection, add_import_exclusion: bool) -> Result<()> {
+        match self {
+            DiskMedia::Movie(movie, is4k) => movie.delete_copy(conn, *is4k, add_import_exclusion),
+            DiskMedia::Serie(serie, is4k) => serie.delete_copy(conn, *is4k)
+        }
+    }
+}
+
+// the copies which can be deleted under pressure, least recently viewed first:
+// protected medias, copies without a known size and medias younger than the age floor are left out
+fn select_medias(movies: Vec<(Movie, bool)>, series: Vec<(Serie, bool)>, age_floor: &Rule) -> Vec<DiskMedia> {
+    let has_age_floor = !age_floor.conditions.is_empty();
+
+    let movies = movies.into_iter()
+        .filter(|(movie, is4k)| !movie.protected && (!has_age_floor || age_floor.matches_movie(movie, *is4k)))
+        .map(|(movie, is4k)| DiskMedia::Movie(movie, is4k));
+    let series = series.into_iter()
+        .filter(|(serie, is4k)| !serie.protected && (!has_age_floor || age_floor.matches_serie(serie, *is4k)))
+        .map(|(serie, is4k)| DiskMedia::Serie(serie, is4k));
+
+    let mut medias: Vec<DiskMedia> = movies.chain(series).filter(|media| media.get_size() > 0).collect();
+    medias.sort_by_key(|media| media.get_last_view());
+    medias
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::rule::Condition;
+
+    const DAY: i32 = 60 * 60 * 24;
+    const GB: i64 = 1_000_000_000;
+
+    fn disk(free_space: u64, high_watermark: u64, low_watermark: u64) -> Disk {
+        Disk {
+            id: 1,
+            path: "/data".to_string(),
+            label: String::new(),
+            total_space: 10_000_000_000,
+            free_space,
+            high_watermark,
+            low_watermark
+        }
+    }
+
+    // a movie with a HD copy viewed days ago
+    fn movie(tmdb_id: i32, last_view_days: i32, size_gb: i64) -> (Movie, bool) {
+        let now = chrono::Utc::now().timestamp() as i32;
+        (Movie {
+            tmdb_id,
+            name: format!("Movie {}", tmdb_id),
+            path_hd: "/movies".to_string(),
+            path_4k: String::new(),
+            rating_key: String::new(),
+            last_view: now - last_view_days * DAY,
+            last_view_source: "tautulli".to_string(),
+            protected: false,
+            deleted: false,
+            added: now - last_view_days * DAY,
+            size_hd: size_gb * GB,
+            size_4k: 0,
+            tags_hd: Vec::new(),
+            tags_4k: Vec::new()
+        }, false)
+    }
+
+    // a serie with a 4K copy viewed days ago
+    fn serie(tvdb_id: i32, last_view_days: i32, size_gb: i64) -> (Serie, bool) {
+        let now = chrono::Utc::now().timestamp() as i32;
+        (Serie {
+            tvdb_id,
+            name: format!("Serie {}", tvdb_id),
+            path_hd: String::new(),
+            path_4k: "/tv4k".to_string(),
+            rating_key: String::new(),
+            last_view: now - last_view_days * DAY,
+            last_view_source: "tautulli".to_string(),
+            protected: false,
+            deleted: false,
+            added: now - last_view_days * DAY,
+            size_hd: 0,
+            size_4k: size_gb * GB,
+            tags_hd: Vec::new(),
+            tags_4k: Vec::new()
+        }, true)
+    }
+
+    fn age_floor(conditions: &[(&str, &str, &str)]) -> Rule {
+        let rule = Rule {
+            id: 1,
+            name: "Test".to_string(),
+            media_type: String::new(),
+            operator: "AND".to_string(),
+            conditions: conditions.iter().map(|(field, operator, value)| Condition {
+                field: field.to_string(),
+                operator: operator.to_string(),
+                value: value.to_string()
+            }).collect()
+        };
+        Rule::age_floor(&[rule])
+    }
+
+    fn disk_space(path: &str) -> DiskSpace {
+        DiskSpace { path: path.to_string(), label: String::new(), free_space: 0, total_space: 0 }
+    }
+
+    fn ids(medias: &[DiskMedia]) -> Vec<String> {
+        medias.iter().map(|media| match media {
+            DiskMedia::Movie(movie, _) => format!("movie {}", movie.tmdb_id),
+            DiskMedia::Serie(serie, _) => format!("serie {}", serie.tvdb_id)
+        }).collect()
+    }
+
+    #[test]
+    fn root_folders_are_on_the_deepest_mount_point() {
+        let disk_spaces = vec![disk_space("/"), disk_space("/mnt/media/"), disk_space("/mnt/media/4k")];
+        assert_eq!(DiskSpace::find(&disk_spaces, "/mnt/media/4k/movies").unwrap().path, "/mnt/media/4k");
+        assert_eq!(DiskSpace::find(&disk_spaces, "/mnt/media/movies").unwrap().path, "/mnt/media/");
+        assert_eq!(DiskSpace::find(&disk_spaces, "/mnt/media4k/movies").unwrap().path, "/");
+        assert_eq!(DiskSpace::find(&disk_spaces, "/mnt/media").unwrap().path, "/mnt/media/");
+    }
+
+    #[test]
+    fn root_folders_on_no_mount_point_have_no_disk() {
+        let disk_spaces = vec![disk_space(""), disk_space("/mnt/media"), disk_space("D:\\")];
+        assert!(DiskSpace::find(&disk_spaces, "/data/movies").is_none());
+        assert!(DiskSpace::find(&disk_spaces, "").is_none());
+        assert_eq!(DiskSpace::find(&disk_spaces, "D:\\Movies").unwrap().path, "D:\\");
+    }
+
+    #[test]
+    fn the_low_watermark_must_be_above_the_high_one() {
+        assert!(disk(0, 100, 200).check_watermarks().is_ok());
+        assert!(disk(0, 0, 0).check_watermarks().is_ok());
+        assert!(disk(0, 100, 100).check_watermarks().is_err());
+        assert!(disk(0, 200, 100).check_watermarks().is_err());
+    }
+
+    #[test]
+    fn only_disks_under_the_high_watermark_are_under_pressure() {
+        assert!(disk(50, 100, 200).is_under_pressure());
+        assert!(!disk(100, 100, 200).is_under_pressure());
+        assert!(!disk(0, 0, 0).is_under_pressure());
+    }
+
+    #[test]
+    fn medias_are_ordered_by_last_view_across_movies_and_series() {
+        let movies = vec![movie(1, 10, 1), movie(2, 300, 1)];
+        let series = vec![serie(3, 100, 1), serie(4, 5, 1)];
+
+        let medias = select_medias(movies, series, &age_floor(&[]));
+        assert_eq!(ids(&medias), ["movie 2", "serie 3", "movie 1", "serie 4"]);
+    }
+
+    #[test]
+    fn protected_and_sizeless_copies_are_not_selected() {
+        let mut protected = movie(1, 300, 1);
+        protected.0.protected = true;
+        let movies = vec![protected, movie(2, 200, 0), movie(3, 100, 1)];
+
+        let medias = select_medias(movies, Vec::new(), &age_floor(&[]));
+        assert_eq!(ids(&medias), ["movie 3"]);
+    }
+
+    #[test]
+    fn medias_younger_than_the_age_floor_are_kept() {
+        let movies = vec![movie(1, 10, 1), movie(2, 100, 1)];
+        let series = vec![serie(3, 20, 1), serie(4, 60, 1)];
+
+        let floor = age_floor(&[("last_view_days", ">", "30"), ("root_folder", "=", "/movies")]);
+        let medias = select_medias(movies, series, &floor);
+        assert_eq!(ids(&medias), ["movie 2", "serie 4"]);
+    }
+
+    #[test]
+    fn deletion_stops_once_the_low_watermark_is_reached() {
+        let gb = GB as u64;
+        let medias = select_medias(vec![movie(1, 300, 2), movie(2, 200, 2), movie(3, 100, 2)], Vec::new(), &age_floor(&[]));
+
+        let mut deleted = Vec::new();
+        let freed = disk(gb, 2 * gb, 4 * gb).delete_until_low_watermark(medias, |media| {
+            deleted.extend(ids(std::slice::from_ref(media)));
+            Ok(())
+        });
+
+        assert_eq!(deleted, ["movie 1", "movie 2"]);
+        assert_eq!(freed, 4 * gb);
+    }
+
+    #[test]
+    fn failed_deletions_free_nothing() {
+        let gb = GB as u64;
+        let medias = select_medias(vec![movie(1, 300, 2), movie(2, 200, 2), movie(3, 100, 2)], Vec::new(), &age_floor(&[]));
+
+        let mut attempts = 0;
+        let freed = disk(gb, 2 * gb, 3 * gb).delete_until_low_watermark(medias, |_| {
+            attempts += 1;
+            if attempts == 1 { Err(Error::Unreachable { url: "http://radarr:7878".to_string(), message: "connection refused".to_string() }) } else { Ok(()) }
+        });
+
+        assert_eq!(attempts, 2);
+        assert_eq!(freed, 2 * gb);
+    }
+}
@@ -0,0 +1,227 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use reqwest::Method;
+use reqwest::blocking::{Client, RequestBuilder};
+
+use crate::error::{Error, Result};
+
+// in seconds, the connection timeout is included in the request one
+const DEFAULT_TIMEOUT: u64 = 30;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_RETRIES: u64 = 3;
+// in milliseconds, doubled on every retry up to the maximum
+const DEFAULT_RETRY_DELAY: u64 = 500;
+const MAX_RETRY_DELAY: u64 = 30_000;
+
+// where a service expects its api key
+#[derive(Debug, Clone, Copy)]
+pub enum ApiKey {
+    // a header, like X-Api-Key or X-Emby-Token
+    Header(&'static str),
+    // a query parameter, like the apikey of tautulli
+    Query(&'static str)
+}
+
+// a client bound to the base url and the api key of a service instance
+pub struct HttpClient {
+    base_url: String,
+    api_key: String,
+    auth: ApiKey,
+    retries: u32,
+    retry_delay: Duration
+}
+
+fn get_env(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+// every service shares the same client, so the connections are reused
+fn get_shared_client() -> Result<&'static Client> {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = Client::builder()
+        .timeout(Duration::from_secs(get_env("HTTP_TIMEOUT", DEFAULT_TIMEOUT)))
+        .connect_timeout(Duration::from_secs(get_env("HTTP_CONNECT_TIMEOUT", DEFAULT_CONNECT_TIMEOUT)))
+        .build()
+        .map_err(|err| Error::Invalid(format!("Could not build the http client : {}", err)))?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+impl HttpClient {
+    pub fn new(base_url: &str, api_key: &str, auth: ApiKey) -> Self {
+        HttpClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            auth,
+            retries: get_env("HTTP_RETRIES", DEFAULT_RETRIES) as u32,
+            retry_delay: Duration::from_millis(get_env("HTTP_RETRY_DELAY", DEFAULT_RETRY_DELAY))
+        }
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn get(&self, path: &str) -> Result<String> {
+        self.send(Method::GET, path, None)
+    }
+
+    // post a JSON body
+    pub fn post(&self, path: &str, body: &str) -> Result<String> {
+        self.send(Method::POST, path, Some(body))
+    }
+
+    pub fn delete(&self, path: &str) -> Result<()> {
+        self.send(Method::DELETE, path, None).map(|_| ())
+    }
+
+    fn build(&self, client: &Client, method: Method, url: &str, body: Option<&str>) -> RequestBuilder {
+        let mut request = client.request(method, url);
+        request = match self.auth {
+            ApiKey::Header(name) => request.header(name, &self.api_key),
+            ApiKey::Query(name) => request.query(&[(name, &self.api_key)])
+        };
+        if let Some(body) = body {
+            request = request.header("Content-Type", "application/json").body(body.to_string());
+        }
+        request
+    }
+
+    // never print the api key, even if a service puts it back in an url or a message
+    fn redact(&self, text: &str) -> String {
+        if self.api_key.is_empty() {
+            text.to_string()
+        } else {
+            text.replace(&self.api_key, "***")
+        }
+    }
+
+    // send the request, retrying with an exponential backoff on timeouts and 5xx statuses,
+    // only for reads as a timed out deletion or creation may have been applied anyway
+    fn send(&self, method: Method, path: &str, body: Option<&str>) -> Result<String> {
+        let url = format!("{}{}", self.base_url, path);
+        let client = get_shared_client()?;
+
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            let result = self.build(client, method.clone(), &url, body).send();
+            let elapsed = start.elapsed().as_millis();
+
+            let error = match result {
+                Ok(response) => {
+                    let status = response.status();
+                    println!("{} {} : {} ({} ms)", method, self.redact(&url), status.as_u16(), elapsed);
+                    if status.is_success() {
+                        return response.text().map_err(|err| Error::from_reqwest(&url, err));
+                    }
+                    Error::from_status(&url, status.as_u16())
+                },
+                Err(err) => {
+                    println!("{} {} : {} ({} ms)", method, self.redact(&url), self.redact(&err.to_string()), elapsed);
+                    Error::from_reqwest(&url, err)
+                }
+            };
+
+            let retryable = (method == Method::GET || method == Method::HEAD) && match error {
+                Error::Timeout { .. } => true,
+                Error::Http { status, .. } => status >= 500,
+                _ => false
+            };
+            if !retryable || attempt >= self.retries {
+                return Err(error);
+            }
+
+            let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+            let delay = self.retry_delay.saturating_mul(factor).min(Duration::from_millis(MAX_RETRY_DELAY));
+            println!("Retrying {} {} in {} ms", method, self.redact(&url), delay.as_millis());
+            std::thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    // answer each connection with the next status, and return the request lines received
+    fn serve(statuses: &[u16]) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let statuses = statuses.to_vec();
+
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                requests.push(line.trim().to_string());
+                // skip the headers, the tested requests have no body
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                write!(reader.get_mut(), "HTTP/1.1 {} Test\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok", status).unwrap();
+            }
+            requests
+        });
+
+        (url, handle)
+    }
+
+    fn client(url: &str, auth: ApiKey) -> HttpClient {
+        let mut client = HttpClient::new(url, "secret", auth).with_retries(2);
+        client.retry_delay = Duration::from_millis(1);
+        client
+    }
+
+    #[test]
+    fn reads_are_retried_on_server_errors() {
+        let (url, server) = serve(&[503, 200]);
+        assert_eq!(client(&url, ApiKey::Header("X-Api-Key")).get("/api/v3/movie").unwrap(), "ok");
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reads_stop_after_the_last_retry() {
+        let (url, server) = serve(&[500, 502, 503]);
+        let result = client(&url, ApiKey::Header("X-Api-Key")).get("/api/v3/movie");
+        assert!(matches!(result, Err(Error::Http { status: 503, .. })));
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn deletions_and_refused_keys_are_not_retried() {
+        let (url, server) = serve(&[503, 401]);
+        let client = client(&url, ApiKey::Header("X-Api-Key"));
+        assert!(matches!(client.delete("/api/v3/movie/1"), Err(Error::Http { status: 503, .. })));
+        assert!(matches!(client.get("/api/v3/movie"), Err(Error::Auth { status: 401, .. })));
+        assert_eq!(server.join().unwrap(), ["DELETE /api/v3/movie/1 HTTP/1.1", "GET /api/v3/movie HTTP/1.1"]);
+    }
+
+    #[test]
+    fn the_api_key_is_redacted() {
+        let (url, server) = serve(&[404]);
+        let client = client(&url, ApiKey::Query("apikey"));
+        let err = client.get("/api/v2?cmd=get_history").unwrap_err();
+        assert!(!err.to_string().contains("secret"));
+        assert_eq!(server.join().unwrap(), ["GET /api/v2?cmd=get_history&apikey=secret HTTP/1.1"]);
+
+        assert_eq!(client.redact("http://tautulli/api/v2?apikey=secret"), "http://tautulli/api/v2?apikey=***");
+        assert_eq!(HttpClient::new(&url, "", ApiKey::Query("apikey")).redact("apikey="), "apikey=");
+    }
+}
@@ -1,104 +1,227 @@
-use std::result;
+use std::collections::HashMap;
 
-use reqwest::blocking::Client;
-use rusqlite::{Connection, Result};
+use rusqlite::Connection;
 use serde_json::Value;
-use super::{movie::Movie, serie::Serie};
+use super::backend::ActivitySource;
+use super::http_client::{ApiKey, HttpClient};
+use super::{jellyfin_item::JellyfinItem, movie::Movie, serie::Serie};
+
+use crate::error::{Error, Result};
+
+// in seconds, an item which could not be resolved is requested again after a week
+const UNRESOLVED_ITEM_TTL: i32 = 60 * 60 * 24 * 7;
 
 #[derive(Debug)]
 pub struct Jellyfin {
     id: i32,
     pub url: String,
-    pub api_key: String
+    pub api_key: String,
+    // playback_reporting or user_data
+    activity_source: String
 }
 
 impl Jellyfin {
-    pub fn new(url: &str, api_key: &str) -> Self {
-        let conn = Connection::open("data.db").unwrap();
-
-        conn.execute("REPLACE INTO jellyfin (url, api_key) VALUES (?, ?)", [url, api_key]).unwrap();
-
-        Jellyfin {
-            id: i32::from(conn.last_insert_rowid() as i32),
-            url: url.to_string(),
-            api_key: api_key.to_string()
-        }
-    }
-
-    pub fn get_all(conn: &Connection) -> Vec<Jellyfin> {
+    pub fn get_all(conn: &Connection) -> Result<Vec<Jellyfin>> {
         let mut stmt = conn.prepare("
-            SELECT id, url, api_key
+            SELECT id, url, api_key, activity_source
             FROM jellyfin
-        ").unwrap();
+        ")?;
 
-        let mut jellyfin_iter = stmt.query_map([], |row| {
+        let jellyfin_iter = stmt.query_map([], |row| {
             Ok(Jellyfin {
                 id: row.get(0)?,
                 url: row.get(1)?,
-                api_key: row.get(2)?
+                api_key: row.get(2)?,
+                activity_source: row.get(3)?
             })
-        }).unwrap();
+        })?;
 
         let mut jellyfins = Vec::new();
 
-        while let Some(result) = jellyfin_iter.next() {
-            jellyfins.push(result.unwrap());
+        for result in jellyfin_iter {
+            jellyfins.push(result?);
         }
 
-        jellyfins
+        Ok(jellyfins)
     }
     
-    // create reqwest_post function
-    fn reqwest_post(&self, url: &str, body: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let client = Client::new();
-        let response = client.post(url).header("X-Emby-Token", &self.api_key).header("Content-Type", "application/json").body(body.to_string()).send()?;
-        
-        if !response.status().is_success() {
-            return Err(format!("Request failed: {}", response.status()).into());
-        }
-
-        response.text().map_err(|err| err.into())
+    fn client(&self) -> HttpClient {
+        HttpClient::new(&self.url, &self.api_key, ApiKey::Header("X-Emby-Token"))
     }
 
-    fn update_media_activity(&self, query: &str) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
-        let url = format!("{}{}", self.url, "/user_usage_stats/submit_custom_query");
+    // get the items of a type played on jellyfin with their last play, from the playback reporting plugin
+    fn get_playback_activity(&self, item_type: &str) -> Result<Vec<(String, i32)>> {
+        let query = format!("SELECT ItemId, strftime('%s', max(DateCreated)) lastView FROM PlaybackActivity WHERE ItemType = '{}' GROUP BY ItemId", item_type);
         let body = format!("{{\"CustomQueryString\":\"{}\"}}", query);
-        let response = self.reqwest_post(url.as_str(), body.as_str())?;
-        
-        // get movies from results array [title, last played] in the response
+        let response = self.client().post("/user_usage_stats/submit_custom_query", body.as_str())?;
+
+        // get items from results array [item id, last played] in the response
         let response_json: serde_json::Value = serde_json::from_str(&response)?;
-        // convert response_json["results"] to array and return error if it's null
-        let results = match response_json["results"].as_array() {
-            Some(results) => results,
-            None => return Err("No results found".into())
-        };
+        let results = response_json["results"].as_array().ok_or_else(|| Error::missing_field("results", "jellyfin playback activity"))?;
 
-        Ok(results.clone())
+        let mut activity = Vec::new();
+        for result in results {
+            if result[1].is_null() {
+                continue;
+            }
+            match self.parse_activity(result) {
+                Ok(item_activity) => activity.push(item_activity),
+                Err(err) => println!("Skipping jellyfin activity : {}", err)
+            };
+        }
+
+        Ok(activity)
     }
 
-    // create function to get the movies activity of the last 2 months using "/user_usage_stats/submit_custom_query" path
-    pub fn update_movies_activity(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-        let query = "SELECT IFNULL(NULLIF(SUBSTR(ItemName, 0, INSTR(ItemName, ' - ')), ''), ItemName) ItemName, strftime('%s', strftime('%s', max(DateCreated)), 'unixepoch') lastView FROM PlaybackActivity WHERE SUBSTR(ItemName, 0, INSTR(ItemName, ' - ')) == '' GROUP BY IFNULL(NULLIF(SUBSTR(ItemName, 0, INSTR(ItemName, ' - ')), ''), ItemName)";
-        let results = self.update_media_activity(query)?;
+    // get the items of a type played by any jellyfin user with their last play, from the user data of every user
+    fn get_user_data_activity(&self, item_type: &str) -> Result<Vec<(String, i32)>> {
+        let response = self.client().get("/Users")?;
+        let users: Value = serde_json::from_str(&response)?;
 
-        let mut quantity_updated = 0;
+        let mut last_plays: HashMap<String, i32> = HashMap::new();
+        let mut quantity_without_date = 0;
+        for user in users.as_array().ok_or_else(|| Error::missing_field("users", "jellyfin users"))? {
+            let user_id = user["Id"].as_str().ok_or_else(|| Error::missing_field("Id", "jellyfin user"))?;
 
-        for result in results {
-            if result[1].is_null() {
-                continue;
+            // the items are paged, every item of the library is read
+            let mut start_index = 0;
+            loop {
+                let response = self.client().get(&format!(
+                    "/Users/{}/Items?Recursive=true&IncludeItemTypes={}&EnableUserData=true&StartIndex={}&Limit=1000",
+                    user_id, item_type, start_index
+                ))?;
+                let json: Value = serde_json::from_str(&response)?;
+                let items = json["Items"].as_array().ok_or_else(|| Error::missing_field("Items", "jellyfin user items"))?;
+
+                for item in items {
+                    // the items never played are ignored, those marked as played by hand have no date and are only counted
+                    let user_data = &item["UserData"];
+                    if user_data["LastPlayedDate"].is_null() {
+                        if user_data["PlayCount"].as_i64().unwrap_or(0) > 0 {
+                            quantity_without_date += 1;
+                        }
+                        continue;
+                    }
+                    let (item_id, last_played) = match self.parse_user_data(item) {
+                        Ok(activity) => activity,
+                        Err(err) => {
+                            println!("Skipping jellyfin user data : {}", err);
+                            continue;
+                        }
+                    };
+
+                    let last_play = last_plays.entry(item_id).or_insert(last_played);
+                    *last_play = (*last_play).max(last_played);
+                }
+
+                start_index += items.len();
+                if items.is_empty() || start_index as i64 >= json["TotalRecordCount"].as_i64().unwrap_or(0) {
+                    break;
+                }
             }
-            let title = result[0].as_str().unwrap();
+        }
 
-            let mut movie = match Movie::get_by_title(&conn, title) {
-                Ok(movie) => movie,
-                Err(_) => continue
+        if quantity_without_date > 0 {
+            println!("Skipped jellyfin user data without last played date : {}", quantity_without_date);
+        }
+        Ok(last_plays.into_iter().collect())
+    }
+
+    // get the items played with their last play from the activity source of the instance
+    fn get_activity(&self, item_type: &str) -> Result<Vec<(String, i32)>> {
+        match self.activity_source.as_str() {
+            "user_data" => self.get_user_data_activity(item_type),
+            _ => self.get_playback_activity(item_type)
+        }
+    }
+
+    // get the items of jellyfin with their provider ids, by chunks to keep the urls short
+    fn get_items(&self, item_ids: &[&str]) -> Result<Vec<Value>> {
+        let mut items = Vec::new();
+
+        for chunk in item_ids.chunks(100) {
+            let response = self.client().get(&format!("/Items?Ids={}&Fields=ProviderIds&Recursive=true", chunk.join(",")))?;
+            let json: Value = serde_json::from_str(&response)?;
+            items.extend(json["Items"].as_array().ok_or_else(|| Error::missing_field("Items", "jellyfin items"))?.iter().cloned());
+        }
+
+        Ok(items)
+    }
+
+    // link the items not known yet to their movie or serie, episodes through the serie of their SeriesId
+    fn resolve_items(&self, conn: &Connection, item_ids: &[&str]) -> Result<()> {
+        let now = chrono::Utc::now().timestamp() as i32;
+        let unknown_ids: Vec<&str> = item_ids.iter().copied()
+            .filter(|item_id| match JellyfinItem::get_by_item_id(conn, self.id, item_id) {
+                Ok(item) => !item.is_resolved() && item.date < now - UNRESOLVED_ITEM_TTL,
+                Err(_) => true
+            })
+            .collect();
+        if unknown_ids.is_empty() {
+            return Ok(());
+        }
+        let items = self.get_items(&unknown_ids)?;
+
+        let mut series_ids: Vec<&str> = items.iter().filter_map(|item| item["SeriesId"].as_str()).collect();
+        series_ids.sort();
+        series_ids.dedup();
+        let series: HashMap<String, i32> = self.get_items(&series_ids)?.iter()
+            .filter_map(|serie| Some((serie["Id"].as_str()?.to_string(), get_provider_id(serie, "Tvdb")?)))
+            .collect();
+
+        let mut quantity_resolved = 0;
+        for item in &items {
+            let item_id = item["Id"].as_str().unwrap_or_default();
+            let media = match item["Type"].as_str() {
+                Some("Movie") => get_provider_id(item, "Tmdb").map(|tmdb_id| ("movie", tmdb_id)),
+                Some("Episode") => item["SeriesId"].as_str().and_then(|serie_id| series.get(serie_id)).map(|tvdb_id| ("serie", *tvdb_id)),
+                _ => None
+            };
+
+            // an item without provider id may get one on a later metadata refresh, so it is only skipped for a while
+            let (media_type, media_id) = match media {
+                Some(media) => {
+                    quantity_resolved += 1;
+                    media
+                },
+                None => {
+                    println!("Skipping jellyfin item {} : no tmdb or tvdb id", item_id);
+                    ("", 0)
+                }
+            };
+            JellyfinItem { jellyfin_id: self.id, item_id: item_id.to_string(), media_type: media_type.to_string(), media_id, date: now }.save(conn)?;
+        }
+
+        // the items removed from jellyfin are not requested again before a while either
+        for item_id in unknown_ids.iter().filter(|item_id| !items.iter().any(|item| item["Id"].as_str() == Some(item_id))) {
+            JellyfinItem { jellyfin_id: self.id, item_id: item_id.to_string(), media_type: String::new(), media_id: 0, date: now }.save(conn)?;
+        }
+
+        println!("Resolved jellyfin items : {}", quantity_resolved);
+        Ok(())
+    }
+
+    // get the last play of every movie from the jellyfin activity, matched on their tmdb id
+    pub fn update_movies_activity(&self, conn: &Connection) -> Result<()> {
+        let activity = self.get_activity("Movie")?;
+        self.resolve_items(conn, &activity.iter().map(|(item_id, _)| item_id.as_str()).collect::<Vec<&str>>())?;
+
+        let mut quantity_updated = 0;
+
+        for (item_id, last_played) in activity {
+            let mut movie = match JellyfinItem::get_by_item_id(conn, self.id, &item_id) {
+                Ok(item) if item.media_type == "movie" => match Movie::get_by_tmdb_id(conn, item.media_id) {
+                    Ok(movie) => movie,
+                    Err(_) => continue
+                },
+                _ => continue
             };
 
             // Set last view timestamp
-            let last_played = self.clean_api_timestamp(&result[1]);
             if movie.last_view < last_played {
                 movie.last_view = last_played;
-                movie.save(&conn)?;
+                movie.last_view_source = "jellyfin".to_string();
+                movie.save(conn)?;
                 quantity_updated += 1;
             }
         };
@@ -107,29 +230,35 @@ impl Jellyfin {
         Ok(())
     }
 
-    pub fn update_series_activity(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-        let query = "SELECT IFNULL(NULLIF(SUBSTR(ItemName, 0, INSTR(ItemName, ' - ')), ''), ItemName) ItemName, strftime('%s', strftime('%s', max(DateCreated)), 'unixepoch') lastView FROM PlaybackActivity WHERE SUBSTR(ItemName, 0, INSTR(ItemName, ' - ')) != '' GROUP BY IFNULL(NULLIF(SUBSTR(ItemName, 0, INSTR(ItemName, ' - ')), ''), ItemName)";
-        let results = self.update_media_activity(query)?;
-
-        let mut quantity_updated = 0;
+    // get the last play of every serie from the jellyfin activity of its episodes, matched on their tvdb id
+    pub fn update_series_activity(&self, conn: &Connection) -> Result<()> {
+        let activity = self.get_activity("Episode")?;
+        self.resolve_items(conn, &activity.iter().map(|(item_id, _)| item_id.as_str()).collect::<Vec<&str>>())?;
 
-        for result in results {
-            if result[1].is_null() {
-                continue;
+        // the last episode played gives the last play of the serie
+        let mut last_plays: HashMap<i32, i32> = HashMap::new();
+        for (item_id, last_played) in activity {
+            if let Ok(item) = JellyfinItem::get_by_item_id(conn, self.id, &item_id) {
+                if item.media_type == "serie" {
+                    let last_play = last_plays.entry(item.media_id).or_insert(last_played);
+                    *last_play = (*last_play).max(last_played);
+                }
             }
+        }
+
+        let mut quantity_updated = 0;
 
-            // get database serie based on the title
-            let title = result[0].as_str().unwrap();
-            let mut serie = match Serie::get_by_title(&conn, title) {
+        for (tvdb_id, last_played) in last_plays {
+            let mut serie = match Serie::get_by_tvdb_id(conn, tvdb_id) {
                 Ok(serie) => serie,
                 Err(_) => continue
             };
 
-            // Set last view timestamp
-            let last_played = self.clean_api_timestamp(&result[1]);
-            if serie.last_view > last_played {
+            // Set last view timestamp, an older play never moves it backwards
+            if serie.last_view < last_played {
                 serie.last_view = last_played;
-                serie.save(&conn)?;
+                serie.last_view_source = "jellyfin".to_string();
+                serie.save(conn)?;
                 quantity_updated += 1;
             }
         };
@@ -138,12 +267,45 @@ impl Jellyfin {
         Ok(())
     }
 
-    fn clean_api_timestamp(&self, timestamp: &Value) -> i32 {
+    // get the item id and the last played timestamp of an activity row
+    fn parse_activity(&self, result: &Value) -> Result<(String, i32)> {
+        let item_id = result[0].as_str().ok_or_else(|| Error::missing_field("ItemId", "jellyfin playback activity"))?;
+        Ok((item_id.to_string(), self.clean_api_timestamp(&result[1])?))
+    }
+
+    // get the item id and the last played timestamp of an item of a user
+    fn parse_user_data(&self, item: &Value) -> Result<(String, i32)> {
+        let item_id = item["Id"].as_str().ok_or_else(|| Error::missing_field("Id", "jellyfin user item"))?;
+        let date = item["UserData"]["LastPlayedDate"].as_str().ok_or_else(|| Error::missing_field("LastPlayedDate", "jellyfin user item"))?;
+        let date = chrono::DateTime::parse_from_rfc3339(date).map_err(|err| Error::Invalid(format!("Invalid date {} : {}", date, err)))?;
+        Ok((item_id.to_string(), date.timestamp() as i32))
+    }
+
+    fn clean_api_timestamp(&self, timestamp: &Value) -> Result<i32> {
         let mut timestamp = timestamp.to_string();
         let mut timestamp_chars = timestamp.chars();
         timestamp_chars.next();
         timestamp_chars.next_back();
         timestamp = timestamp_chars.as_str().to_string();
-        timestamp.parse::<i32>().unwrap()
+        timestamp.parse::<i32>().map_err(|err| Error::Invalid(format!("Invalid timestamp {} : {}", timestamp, err)))
     }
-}
\ No newline at end of file
+}
+
+impl ActivitySource for Jellyfin {
+    fn get_name(&self) -> &str {
+        "jellyfin"
+    }
+
+    // the series are updated even if the movies fail
+    fn update_activity(&mut self, conn: &Connection) -> Result<()> {
+        let movies = self.update_movies_activity(conn);
+        let series = self.update_series_activity(conn);
+        movies?;
+        series
+    }
+}
+
+// get a tmdb or tvdb id of a jellyfin item
+fn get_provider_id(item: &Value, provider: &str) -> Option<i32> {
+    item["ProviderIds"][provider].as_str()?.parse().ok()
+}
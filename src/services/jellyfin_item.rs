@@ -0,0 +1,62 @@
+use rusqlite::{Connection, params};
+
+use crate::error::{Error, Result};
+
+// link between a jellyfin item id and the movie (tmdb id) or serie (tvdb id) it belongs to,
+// an item which could not be resolved has no media type so it is not requested again before a while
+#[derive(Debug)]
+pub struct JellyfinItem {
+    // the jellyfin instance knowing the item, 0 when linked by jellyseerr
+    pub jellyfin_id: i32,
+    pub item_id: String,
+    pub media_type: String,
+    pub media_id: i32,
+    // when the item was resolved
+    pub date: i32
+}
+
+impl JellyfinItem {
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        conn.execute("
+            REPLACE INTO jellyfin_item (jellyfin_id, item_id, media_type, media_id, date)
+            VALUES (?, ?, ?, ?, ?)
+        ", params![
+            &self.jellyfin_id,
+            &self.item_id,
+            &self.media_type,
+            &self.media_id,
+            &self.date,
+        ])?;
+        Ok(())
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        !self.media_type.is_empty()
+    }
+
+    // get an item of a jellyfin instance, or the link given by jellyseerr, a resolved one first
+    pub fn get_by_item_id(conn: &Connection, jellyfin_id: i32, item_id: &str) -> Result<JellyfinItem> {
+        let mut stmt = conn.prepare("
+            SELECT jellyfin_id, item_id, media_type, media_id, date
+            FROM jellyfin_item
+            WHERE jellyfin_id IN (0, ?) AND item_id = ?
+            ORDER BY media_type != '' DESC, jellyfin_id DESC
+        ")?;
+
+        let mut item_iter = stmt.query_map(params![jellyfin_id, item_id], |row| {
+            Ok(JellyfinItem {
+                jellyfin_id: row.get(0)?,
+                item_id: row.get(1)?,
+                media_type: row.get(2)?,
+                media_id: row.get(3)?,
+                date: row.get(4)?
+            })
+        })?;
+
+        if let Some(result) = item_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Jellyfin item {}", item_id)))
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use rusqlite::Connection;
+
+use super::backend::RequestManager;
+use super::jellyfin_item::JellyfinItem;
+use super::seerr::Seerr;
+
+use crate::error::Result;
+
+// jellyseerr knows the medias by their jellyfin item id
+#[derive(Debug)]
+pub struct Jellyseerr(Seerr);
+
+impl Jellyseerr {
+    // get all jellyseerrs from database
+    pub fn get_all(conn: &Connection) -> Result<Vec<Jellyseerr>> {
+        Ok(Seerr::get_all(conn, "jellyseerr", "jellyfinMediaId")?.into_iter().map(Jellyseerr).collect())
+    }
+
+    // get the jellyfin item ids of every movie and serie known by jellyseerr
+    pub fn get_jellyfin_items(&self) -> Result<Vec<JellyfinItem>> {
+        let mut items: Vec<JellyfinItem> = Vec::new();
+
+        for (media_type, id_field) in [("movie", "tmdbId"), ("tv", "tvdbId")] {
+            for media in self.0.get_medias(media_type)? {
+                let media_id = match media[id_field].as_i64() {
+                    Some(media_id) => media_id as i32,
+                    None => continue
+                };
+
+                for item_field in ["jellyfinMediaId", "jellyfinMediaId4k"] {
+                    if let Some(item_id) = media[item_field].as_str() {
+                        items.push(JellyfinItem {
+                            jellyfin_id: 0,
+                            item_id: item_id.to_string(),
+                            media_type: if media_type == "movie" { "movie".to_string() } else { "serie".to_string() },
+                            media_id,
+                            date: chrono::Utc::now().timestamp() as i32
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(items)
+    }
+
+    // save the jellyfin item ids so jellyfin activity can be linked to the medias
+    pub fn update_db_jellyfin_items(&self, conn: &Connection) -> Result<()> {
+        let items = self.get_jellyfin_items()?;
+
+        for item in &items {
+            item.save(conn)?;
+        }
+
+        println!("Saved jellyfin items : {}", items.len());
+        Ok(())
+    }
+}
+
+impl RequestManager for Jellyseerr {
+    fn get_name(&self) -> &str {
+        self.0.get_name()
+    }
+
+    fn update_db_movies(&self, conn: &Connection) -> Result<()> {
+        self.0.update_db_movies(conn)
+    }
+
+    fn update_db_series(&self, conn: &Connection) -> Result<()> {
+        self.0.update_db_series(conn)
+    }
+
+    fn update_db_items(&self, conn: &Connection) -> Result<()> {
+        self.update_db_jellyfin_items(conn)
+    }
+
+    fn discover_media_managers(&self, conn: &Connection) -> Result<()> {
+        self.0.discover_media_managers(conn)
+    }
+}
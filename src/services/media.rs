@@ -0,0 +1,14 @@
+// helpers shared by the services reading movies and series
+
+// convert a service date ("2023-07-14T09:23:00Z") to a unix timestamp, 0 if invalid
+pub fn convert_date_to_timestamp(date: &str) -> i32 {
+    match chrono::DateTime::parse_from_rfc3339(date) {
+        Ok(date) => date.timestamp() as i32,
+        Err(_) => 0
+    }
+}
+
+// tags are stored as a comma separated list
+pub fn split_tags(tags: String) -> Vec<String> {
+    tags.split(',').filter(|tag| !tag.is_empty()).map(|tag| tag.to_string()).collect()
+}
@@ -1,8 +1,13 @@
-use rusqlite::{Connection, params};
+use std::collections::HashMap;
 
-use super::serie::Serie;
+use rusqlite::{Connection, Row, params};
+use serde::Serialize;
 
-#[derive(Debug)]
+use super::{deletion::Deletion, disk::Disk, media::{convert_date_to_timestamp, split_tags}, path::{GetDisk, RadarrPath}, rule::Rule, radarr::Radarr};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Movie {
     pub tmdb_id: i32,
     pub name: String,
@@ -10,14 +15,42 @@ pub struct Movie {
     pub path_4k: String,
     pub rating_key: String,
     pub last_view: i32,
-    pub protected: bool
+    // where the last view comes from (overseerr, jellyseerr, radarr, sonarr, tautulli or jellyfin)
+    pub last_view_source: String,
+    pub protected: bool,
+    pub deleted: bool,
+    pub added: i32,
+    pub size_hd: i64,
+    pub size_4k: i64,
+    // radarr/sonarr tag names of each copy
+    pub tags_hd: Vec<String>,
+    pub tags_4k: Vec<String>
 }
 
 impl Movie {
-    pub fn save(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    fn from_row(row: &Row) -> rusqlite::Result<Movie> {
+        Ok(Movie {
+            tmdb_id: row.get(0)?,
+            name: row.get(1)?,
+            path_hd: row.get(2)?,
+            path_4k: row.get(3)?,
+            rating_key: row.get(4)?,
+            last_view: row.get(5)?,
+            last_view_source: row.get(6)?,
+            protected: row.get(7)?,
+            deleted: row.get(8)?,
+            added: row.get(9)?,
+            size_hd: row.get(10)?,
+            size_4k: row.get(11)?,
+            tags_hd: split_tags(row.get(12)?),
+            tags_4k: split_tags(row.get(13)?)
+        })
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
         conn.execute("
-            REPLACE INTO movie (tmdb_id, name, path_hd, path_4k, rating_key, last_view, protected)
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            REPLACE INTO movie (tmdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         ", params![
             &self.tmdb_id,
             &self.name,
@@ -25,29 +58,26 @@ impl Movie {
             &self.path_4k,
             &self.rating_key,
             &self.last_view,
+            &self.last_view_source,
             &self.protected,
+            &self.deleted,
+            &self.added,
+            &self.size_hd,
+            &self.size_4k,
+            &self.tags_hd.join(","),
+            &self.tags_4k.join(","),
         ])?;
         Ok(())
     }
 
     // create static function that returns all database movies
-    pub fn get_all(conn: &Connection) -> Result<Vec<Movie>, Box<dyn std::error::Error>> {
+    pub fn get_all(conn: &Connection) -> Result<Vec<Movie>> {
         let mut stmt = conn.prepare("
-            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, protected
+            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
             FROM movie
         ")?;
 
-        let movies_iter = stmt.query_map([], |row| {
-            Ok(Movie {
-                tmdb_id: row.get(0)?,
-                name: row.get(1)?,
-                path_hd: row.get(2)?,
-                path_4k: row.get(3)?,
-                rating_key: row.get(4)?,
-                last_view: row.get(5)?,
-                protected: row.get(6)?
-            })
-        })?;
+        let movies_iter = stmt.query_map([], Movie::from_row)?;
 
         let mut movies = Vec::new();
         for movie in movies_iter {
@@ -58,103 +88,171 @@ impl Movie {
     }
 
     // create from_radarr_json function
-    pub fn from_radarr_json(json: &serde_json::Value, is4k: bool) -> Self {
+    pub fn from_radarr_json(json: &serde_json::Value, is4k: bool, tags: &HashMap<i64, String>) -> Result<Self> {
+        // until a view is found, the movie is considered viewed when added
+        let added = convert_date_to_timestamp(json["added"].as_str().unwrap_or(""));
         let mut movie = Movie {
-            tmdb_id: json["tmdbId"].as_i64().unwrap() as i32,
-            name: json["title"].as_str().unwrap().to_string(),
+            tmdb_id: json["tmdbId"].as_i64().ok_or_else(|| Error::missing_field("tmdbId", "radarr movie"))? as i32,
+            name: json["title"].as_str().ok_or_else(|| Error::missing_field("title", "radarr movie"))?.to_string(),
             path_hd: String::new(),
             path_4k: String::new(),
             rating_key: String::new(),
-            last_view: 0,
-            protected: false
+            last_view: added,
+            last_view_source: "radarr".to_string(),
+            protected: false,
+            deleted: false,
+            added,
+            size_hd: 0,
+            size_4k: 0,
+            tags_hd: Vec::new(),
+            tags_4k: Vec::new()
         };
+        // resolve the tag ids to their names
+        let tag_names = json["tags"].as_array().map(|ids| {
+            ids.iter().filter_map(|id| id.as_i64().and_then(|id| tags.get(&id)).cloned()).collect()
+        }).unwrap_or_default();
         if is4k {
-            movie.path_4k = json["rootFolderPath"].as_str().unwrap().to_string();
+            movie.tags_4k = tag_names;
+            movie.path_4k = json["rootFolderPath"].as_str().ok_or_else(|| Error::missing_field("rootFolderPath", "radarr movie"))?.to_string();
+            movie.size_4k = json["sizeOnDisk"].as_i64().or(json["movieFile"]["size"].as_i64()).unwrap_or(0);
         } else {
-            movie.path_hd = json["rootFolderPath"].as_str().unwrap().to_string();
+            movie.tags_hd = tag_names;
+            movie.path_hd = json["rootFolderPath"].as_str().ok_or_else(|| Error::missing_field("rootFolderPath", "radarr movie"))?.to_string();
+            movie.size_hd = json["sizeOnDisk"].as_i64().or(json["movieFile"]["size"].as_i64()).unwrap_or(0);
         }
-        movie
+        Ok(movie)
     }
 
-    // function to get a movie by his title
-    pub fn get_by_title(conn: &Connection, title: &str) -> Result<Movie, Box<dyn std::error::Error>> {
+    pub fn get_by_tmdb_id(conn: &Connection, tmdb_id: i32) -> Result<Movie> {
         let mut stmt = conn.prepare("
-            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, protected
+            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
             FROM movie
-            WHERE trim(lower(name)) = trim(lower(?))
+            WHERE tmdb_id = ?
         ")?;
 
-        let mut movie_iter = stmt.query_map([title], |row| {
-            Ok(Movie {
-                tmdb_id: row.get(0)?,
-                name: row.get(1)?,
-                path_hd: row.get(2)?,
-                path_4k: row.get(3)?,
-                rating_key: row.get(4)?,
-                last_view: row.get(5)?,
-                protected: row.get(6)?
-            })
-        })?;
+        let mut movie_iter = stmt.query_map([tmdb_id], Movie::from_row)?;
 
         if let Some(result) = movie_iter.next() {
             result.map_err(|err| err.into())
         } else {
-            Err(format!("Movie {} not found", title).into())
+            Err(Error::NotFound(format!("Movie {}", tmdb_id)))
         }
     }
 
-    pub fn get_movies_to_delete(conn: &Connection, last_view: i32) -> Result<Vec<Movie>, Box<dyn std::error::Error>> {
+    // get the copies of the unprotected movies matching at least one of the rules, with true if the copy is the 4K one
+    pub fn get_movies_to_delete(conn: &Connection, rules: &[Rule]) -> Result<Vec<(Movie, bool)>> {
         let mut stmt = conn.prepare("
-            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, protected
+            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
             FROM movie
-            WHERE last_view < ?
+            WHERE deleted = 0 AND protected = 0
         ")?;
 
-        let mut movie_iter = stmt.query_map([last_view], |row| {
-            Ok(Movie {
-                tmdb_id: row.get(0)?,
-                name: row.get(1)?,
-                path_hd: row.get(2)?,
-                path_4k: row.get(3)?,
-                rating_key: row.get(4)?,
-                last_view: row.get(5)?,
-                protected: row.get(6)?
-            })
-        })?;
+        let movie_iter = stmt.query_map([], Movie::from_row)?;
 
         let mut movies = Vec::new();
-        while let Some(result) = movie_iter.next() {
-            movies.push(result?);
+        for result in movie_iter {
+            let movie = result?;
+            for is4k in [false, true] {
+                let path = if is4k { &movie.path_4k } else { &movie.path_hd };
+                if !path.is_empty() && rules.iter().any(|rule| rule.matches_movie(&movie, is4k)) {
+                    movies.push((movie.clone(), is4k));
+                }
+            }
         }
 
         Ok(movies)
     }
 
-    pub fn get_by_rating_key(conn: &Connection, rating_key: &str) -> Result<Movie, Box<dyn std::error::Error>> {
+    pub fn get_by_rating_key(conn: &Connection, rating_key: &str) -> Result<Movie> {
         let mut stmt = conn.prepare("
-            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, protected
+            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
             FROM movie
             WHERE rating_key = ?
         ")?;
 
-        let mut movie_iter = stmt.query_map([rating_key], |row| {
-            let movie = Movie {
-                tmdb_id: row.get(0)?,
-                name: row.get(1)?,
-                path_hd: row.get(2)?,
-                path_4k: row.get(3)?,
-                rating_key: row.get(4)?,
-                last_view: row.get(5)?,
-                protected: row.get(6)?
-            };
-            Ok(movie)
-        })?;
+        let mut movie_iter = stmt.query_map([rating_key], Movie::from_row)?;
 
         if let Some(result) = movie_iter.next() {
             result.map_err(|err| err.into())
         } else {
-            Err(format!("Movie not found for the rating key : {}", rating_key).into())
+            Err(Error::NotFound(format!("Movie with the rating key {}", rating_key)))
+        }
+    }
+
+    // delete the HD or 4K copy of the movie from the radarr instance owning its root folder and record the outcome
+    pub fn delete_copy(&mut self, conn: &Connection, is4k: bool, add_import_exclusion: bool) -> Result<()> {
+        let path = if is4k { self.path_4k.clone() } else { self.path_hd.clone() };
+        if path.is_empty() {
+            return Ok(());
+        }
+
+        let radarr = Radarr::get_by_path(conn, &path, is4k)?;
+        let result = radarr.delete_movie(self.tmdb_id, add_import_exclusion);
+        Deletion::new("movie", self.tmdb_id, &self.name, radarr.get_id(), &path, &result).save(conn)?;
+        result?;
+
+        // only the column of the copy is updated, the other copy may have been deleted since the movie was read
+        let column = if is4k { "path_4k" } else { "path_hd" };
+        conn.execute(&format!("UPDATE movie SET {column} = '' WHERE tmdb_id = ?"), [self.tmdb_id])?;
+        // only flag the movie once it is gone from every instance, so failed ones are retried next run
+        conn.execute("UPDATE movie SET deleted = 1 WHERE tmdb_id = ? AND path_hd = '' AND path_4k = ''", [self.tmdb_id])?;
+
+        if is4k {
+            self.path_4k = String::new();
+        } else {
+            self.path_hd = String::new();
         }
+        self.deleted = self.path_hd.is_empty() && self.path_4k.is_empty();
+        Ok(())
+    }
+
+    // get the disk storing the HD or 4K copy of the movie
+    pub fn get_disk(&self, conn: &Connection, is4k: bool) -> Result<Disk> {
+        let path = if is4k { &self.path_4k } else { &self.path_hd };
+        RadarrPath::get_by_path(conn, path, is4k)?.get_disk(conn)
+    }
+
+    // get the space that would be freed on the disk by deleting all the movies stored on it
+    pub fn get_size_on_disk(conn: &Connection, disk_id: i32) -> Result<i64> {
+        let size = Movie::get_on_disk(conn, disk_id)?.iter()
+            .map(|(movie, is4k)| if *is4k { movie.size_4k } else { movie.size_hd })
+            .sum();
+
+        Ok(size)
+    }
+
+    // get movies with a copy on the disk, with true if the copy is the 4K one
+    pub fn get_on_disk(conn: &Connection, disk_id: i32) -> Result<Vec<(Movie, bool)>> {
+        let mut stmt = conn.prepare("
+            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k, 0
+            FROM movie
+            WHERE deleted = 0 AND path_hd IN (
+                SELECT radarr_path.path
+                FROM radarr_path
+                INNER JOIN radarr ON radarr.id = radarr_path.radarr_id
+                WHERE radarr_path.disk_id = ?1 AND radarr.is4k = 0
+            )
+            UNION ALL
+            SELECT tmdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k, 1
+            FROM movie
+            WHERE deleted = 0 AND path_4k IN (
+                SELECT radarr_path.path
+                FROM radarr_path
+                INNER JOIN radarr ON radarr.id = radarr_path.radarr_id
+                WHERE radarr_path.disk_id = ?1 AND radarr.is4k = 1
+            )
+        ")?;
+
+        let movie_iter = stmt.query_map([disk_id], |row| {
+            Ok((Movie::from_row(row)?, row.get(14)?))
+        })?;
+
+        let mut movies = Vec::new();
+        for result in movie_iter {
+            movies.push(result?);
+        }
+
+        Ok(movies)
     }
 }
 
@@ -163,4 +261,4 @@ impl PartialEq for Movie {
     fn eq(&self, other: &Self) -> bool {
         self.tmdb_id == other.tmdb_id
     }
-}
\ No newline at end of file
+}
@@ -1,48 +1,106 @@
 use super::disk::Disk;
-use rusqlite::{Connection, Result, params};
+use rusqlite::{Connection, params};
 
+use crate::error::{Error, Result};
+
+// a root folder of a sonarr instance, only its path and disk are read back
 pub struct SonarrPath {
-    sonarr_id: i32,
     path: String,
-    disk_id : i32
+    disk_id : Option<i32>
 }
 
 impl SonarrPath {
-    pub fn new(conn: &Connection, sonarr_id: i32, path: &str, disk_id: i32) -> Self {
-        conn.execute("REPLACE INTO sonarr_path (sonarr_id, path, disk_id) VALUES (?, ?, ?)", params![sonarr_id, path, disk_id]).unwrap();
-        Self {
-            sonarr_id: sonarr_id,
+    pub fn new(conn: &Connection, sonarr_id: i32, path: &str, disk_id: Option<i32>) -> Result<Self> {
+        conn.execute("REPLACE INTO sonarr_path (sonarr_id, path, disk_id) VALUES (?, ?, ?)", params![sonarr_id, path, disk_id])?;
+        Ok(Self {
             path: path.to_string(),
-            disk_id: disk_id
+            disk_id
+        })
+    }
+
+    // get the root folder of the HD or 4K sonarr instances
+    pub fn get_by_path(conn: &Connection, path: &str, is4k: bool) -> Result<SonarrPath> {
+        let mut stmt = conn.prepare("
+            SELECT sonarr_path.path, sonarr_path.disk_id
+            FROM sonarr_path
+            INNER JOIN sonarr ON sonarr.id = sonarr_path.sonarr_id
+            WHERE sonarr_path.path = ? AND sonarr.is4k = ?
+        ")?;
+
+        let mut path_iter = stmt.query_map(params![path, is4k], |row| {
+            Ok(SonarrPath {
+                path: row.get(0)?,
+                disk_id: row.get(1)?
+            })
+        })?;
+
+        if let Some(result) = path_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Sonarr root folder {}", path)))
         }
     }
 }
 
+// a root folder of a radarr instance, only its path and disk are read back
 #[derive(Debug)]
 pub struct RadarrPath {
-    radarr_id: i32,
     path: String,
-    disk_id : i32
+    disk_id : Option<i32>
 }
 
 impl RadarrPath {
-    pub fn new(conn: &Connection, radarr_id: i32, path: &str, disk_id: i32) -> Self {
-        conn.execute("REPLACE INTO radarr_path (radarr_id, path, disk_id) VALUES (?, ?, ?)", params![radarr_id, path, disk_id]).unwrap();
+    pub fn new(conn: &Connection, radarr_id: i32, path: &str, disk_id: Option<i32>) -> Result<Self> {
+        conn.execute("REPLACE INTO radarr_path (radarr_id, path, disk_id) VALUES (?, ?, ?)", params![radarr_id, path, disk_id])?;
 
-        Self {
-            radarr_id: radarr_id,
+        Ok(Self {
             path: path.to_string(),
-            disk_id: disk_id
+            disk_id
+        })
+    }
+
+    // get the root folder of the HD or 4K radarr instances
+    pub fn get_by_path(conn: &Connection, path: &str, is4k: bool) -> Result<RadarrPath> {
+        let mut stmt = conn.prepare("
+            SELECT radarr_path.path, radarr_path.disk_id
+            FROM radarr_path
+            INNER JOIN radarr ON radarr.id = radarr_path.radarr_id
+            WHERE radarr_path.path = ? AND radarr.is4k = ?
+        ")?;
+
+        let mut path_iter = stmt.query_map(params![path, is4k], |row| {
+            Ok(RadarrPath {
+                path: row.get(0)?,
+                disk_id: row.get(1)?
+            })
+        })?;
+
+        if let Some(result) = path_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Radarr root folder {}", path)))
         }
     }
 }
 
-trait GetDisk {
-    fn get_disk(&self) -> Result<Disk, Box<dyn std::error::Error>>;
+pub trait GetDisk {
+    fn get_disk(&self, conn: &Connection) -> Result<Disk>;
 }
 
 impl GetDisk for SonarrPath {
-    fn get_disk(&self) -> Result<Disk, Box<dyn std::error::Error>> {
-        Disk::get_by_id(self.disk_id)
+    fn get_disk(&self, conn: &Connection) -> Result<Disk> {
+        match self.disk_id {
+            Some(disk_id) => Disk::get_by_id(conn, disk_id),
+            None => Err(Error::NotFound(format!("No disk for the root folder {}", self.path)))
+        }
+    }
+}
+
+impl GetDisk for RadarrPath {
+    fn get_disk(&self, conn: &Connection) -> Result<Disk> {
+        match self.disk_id {
+            Some(disk_id) => Disk::get_by_id(conn, disk_id),
+            None => Err(Error::NotFound(format!("No disk for the root folder {}", self.path)))
+        }
     }
 }
\ No newline at end of file
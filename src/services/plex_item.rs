@@ -0,0 +1,84 @@
+use rusqlite::{Connection, Row, params};
+
+use crate::error::{Error, Result};
+
+// link between a plex rating key of a tautulli instance and the movie (tmdb id) or serie (tvdb id) it belongs to,
+// a rating key without tmdb or tvdb id has no media type so it is not requested again before a while
+#[derive(Debug)]
+pub struct PlexItem {
+    pub tautulli_id: i32,
+    pub rating_key: String,
+    pub media_type: String,
+    pub media_id: i32,
+    // when the rating key was resolved
+    pub date: i32
+}
+
+impl PlexItem {
+    fn from_row(row: &Row) -> rusqlite::Result<PlexItem> {
+        Ok(PlexItem {
+            tautulli_id: row.get(0)?,
+            rating_key: row.get(1)?,
+            media_type: row.get(2)?,
+            media_id: row.get(3)?,
+            date: row.get(4)?
+        })
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        conn.execute("
+            REPLACE INTO plex_item (tautulli_id, rating_key, media_type, media_id, date)
+            VALUES (?, ?, ?, ?, ?)
+        ", params![
+            &self.tautulli_id,
+            &self.rating_key,
+            &self.media_type,
+            &self.media_id,
+            &self.date,
+        ])?;
+        Ok(())
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        !self.media_type.is_empty()
+    }
+
+    pub fn delete(&self, conn: &Connection) -> Result<()> {
+        conn.execute("DELETE FROM plex_item WHERE tautulli_id = ? AND rating_key = ?", params![self.tautulli_id, self.rating_key])?;
+        Ok(())
+    }
+
+    // get every item known by a tautulli instance
+    pub fn get_all(conn: &Connection, tautulli_id: i32) -> Result<Vec<PlexItem>> {
+        let mut stmt = conn.prepare("
+            SELECT tautulli_id, rating_key, media_type, media_id, date
+            FROM plex_item
+            WHERE tautulli_id = ?
+        ")?;
+
+        let item_iter = stmt.query_map([tautulli_id], PlexItem::from_row)?;
+
+        let mut items = Vec::new();
+        for item in item_iter {
+            items.push(item?);
+        }
+
+        Ok(items)
+    }
+
+    pub fn get_by_rating_key(conn: &Connection, tautulli_id: i32, rating_key: &str) -> Result<PlexItem> {
+        let mut stmt = conn.prepare("
+            SELECT tautulli_id, rating_key, media_type, media_id, date
+            FROM plex_item
+            WHERE tautulli_id = ? AND rating_key = ?
+        ")?;
+
+        let mut item_iter = stmt.query_map(params![tautulli_id, rating_key], PlexItem::from_row)?;
+
+        if let Some(result) = item_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Plex item {}", rating_key)))
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use rusqlite::{Connection, Row, params};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+// radarr/sonarr tag protecting the medias when PROTECTED_TAGS is not set
+const DEFAULT_PROTECTED_TAGS: &str = "keep";
+
+// keeps a movie or a serie, matched by id or by title, from being deleted
+#[derive(Debug, Serialize)]
+pub struct Protection {
+    pub id: i32,
+    // "movie" or "serie"
+    pub media_type: String,
+    // tmdb id for movies, tvdb id for series, 0 to match on the title
+    pub media_id: i32,
+    pub title: String,
+    // unix timestamp until which the media is protected, 0 for ever
+    pub until: i32,
+    pub protected_by: String,
+    pub reason: String,
+    // when the protection was added
+    pub date: i32
+}
+
+// a line of a protection import file, every column but the media type can be left empty
+#[derive(Deserialize)]
+struct ProtectionRecord {
+    media_type: String,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
+    media_id: Option<i32>,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    until: String,
+    #[serde(default)]
+    protected_by: String,
+    #[serde(default)]
+    reason: String
+}
+
+impl Protection {
+    // protect a media by id if the target is a number, by title otherwise
+    pub fn new(media_type: &str, target: &str, until: i32, protected_by: &str, reason: &str) -> Self {
+        let (media_id, title) = match target.trim().parse::<i32>() {
+            Ok(media_id) => (media_id, String::new()),
+            Err(_) => (0, target.trim().to_string())
+        };
+
+        Protection {
+            id: 0,
+            media_type: media_type.to_string(),
+            media_id,
+            title,
+            until,
+            protected_by: protected_by.to_string(),
+            reason: reason.to_string(),
+            date: 0
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Protection> {
+        Ok(Protection {
+            id: row.get(0)?,
+            media_type: row.get(1)?,
+            media_id: row.get(2)?,
+            title: row.get(3)?,
+            until: row.get(4)?,
+            protected_by: row.get(5)?,
+            reason: row.get(6)?,
+            date: row.get(7)?
+        })
+    }
+
+    pub fn get_all(conn: &Connection) -> Result<Vec<Protection>> {
+        let mut stmt = conn.prepare("
+            SELECT id, media_type, media_id, title, until, protected_by, reason, date
+            FROM protection
+        ")?;
+        let protection_iter = stmt.query_map([], Protection::from_row)?;
+
+        let mut protections = Vec::new();
+        for protection in protection_iter {
+            protections.push(protection?);
+        }
+
+        Ok(protections)
+    }
+
+    // convert a "YYYY-MM-DD" date to a timestamp, an empty date protects for ever
+    pub fn parse_until(until: &str) -> Result<i32> {
+        if until.trim().is_empty() {
+            return Ok(0);
+        }
+
+        let date = chrono::NaiveDate::parse_from_str(until.trim(), "%Y-%m-%d")
+            .map_err(|err| Error::Invalid(format!("Invalid date {} : {}", until, err)))?;
+        Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp() as i32)
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        if self.media_type != "movie" && self.media_type != "serie" {
+            return Err(Error::Invalid(format!("Unknown media type : {}", self.media_type)));
+        }
+        if self.media_id == 0 && self.title.is_empty() {
+            return Err(Error::Invalid("A protection needs a media id or a title".to_string()));
+        }
+
+        conn.execute("
+            INSERT INTO protection (media_type, media_id, title, until, protected_by, reason, date)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        ", params![
+            &self.media_type,
+            &self.media_id,
+            &self.title,
+            &self.until,
+            &self.protected_by,
+            &self.reason,
+            chrono::Utc::now().timestamp() as i32,
+        ])?;
+        Ok(())
+    }
+
+    // remove the protections of a media by id or by title
+    pub fn delete(conn: &Connection, media_type: &str, target: &str) -> Result<usize> {
+        let protection = Protection::new(media_type, target, 0, "", "");
+        let quantity = conn.execute("
+            DELETE FROM protection
+            WHERE media_type = ? AND media_id = ? AND trim(lower(title)) = trim(lower(?))
+        ", params![protection.media_type, protection.media_id, protection.title])?;
+        Ok(quantity)
+    }
+
+    // save every protection of a CSV file with a "media_type,media_id,title,until,protected_by,reason" header,
+    // nothing is saved if a line is invalid
+    pub fn import(conn: &Connection, path: &str) -> Result<usize> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let transaction = conn.unchecked_transaction()?;
+        let mut quantity = 0;
+
+        for record in reader.deserialize() {
+            let record: ProtectionRecord = record?;
+            let protection = Protection {
+                id: 0,
+                media_type: record.media_type,
+                media_id: record.media_id.unwrap_or(0),
+                title: record.title.trim().to_string(),
+                until: Protection::parse_until(&record.until)?,
+                protected_by: record.protected_by,
+                reason: record.reason,
+                date: 0
+            };
+            protection.save(&transaction)?;
+            quantity += 1;
+        }
+
+        transaction.commit()?;
+        Ok(quantity)
+    }
+
+    // radarr/sonarr tags protecting the medias, from PROTECTED_TAGS
+    fn get_protected_tags() -> Vec<String> {
+        let tags = std::env::var("PROTECTED_TAGS").unwrap_or_else(|_| DEFAULT_PROTECTED_TAGS.to_string());
+        tags.split(',').map(|tag| tag.trim().to_lowercase()).filter(|tag| !tag.is_empty()).collect()
+    }
+
+    // refresh the protected flag of every movie and serie from the active protections and the protected tags
+    pub fn update_medias(conn: &Connection) -> Result<()> {
+        let now = chrono::Utc::now().timestamp() as i32;
+        let tags = Protection::get_protected_tags();
+
+        for (table, id_column) in [("movie", "tmdb_id"), ("serie", "tvdb_id")] {
+            conn.execute(&format!("
+                UPDATE {table} SET protected = EXISTS (
+                    SELECT 1
+                    FROM protection
+                    WHERE protection.media_type = ?1
+                    AND (protection.until = 0 OR protection.until > ?2)
+                    AND (
+                        protection.media_id = {table}.{id_column}
+                        OR (protection.media_id = 0 AND trim(lower(protection.title)) = trim(lower({table}.name)))
+                    )
+                )
+            "), params![table, now])?;
+
+            for tag in &tags {
+                conn.execute(&format!("
+                    UPDATE {table} SET protected = 1
+                    WHERE instr(',' || lower(tags_hd) || ',' || lower(tags_4k) || ',', ',' || ? || ',') > 0
+                "), [tag])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("
+            CREATE TABLE protection (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                media_type TEXT,
+                media_id INTEGER DEFAULT 0,
+                title TEXT DEFAULT '',
+                until INTEGER DEFAULT 0,
+                protected_by TEXT DEFAULT '',
+                reason TEXT DEFAULT '',
+                date INTEGER
+            )",
+            []
+        ).unwrap();
+        conn
+    }
+
+    // write a protection import file in the temporary directory
+    fn import_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lunager-{}-{}.csv", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn until_is_a_date_or_for_ever() {
+        assert_eq!(Protection::parse_until("").unwrap(), 0);
+        assert_eq!(Protection::parse_until("  ").unwrap(), 0);
+        assert_eq!(Protection::parse_until("2024-01-01").unwrap(), 1704067200);
+        assert!(Protection::parse_until("01/01/2024").is_err());
+        assert!(Protection::parse_until("2024-02-30").is_err());
+    }
+
+    #[test]
+    fn import_saves_every_line() {
+        let conn = connection();
+        let path = import_file("import", "\
+media_type,media_id,title,until,protected_by,reason
+movie,603,,2030-01-01,alice,favorite
+serie,, Breaking Bad ,,bob,
+");
+
+        assert_eq!(Protection::import(&conn, &path).unwrap(), 2);
+        std::fs::remove_file(path).unwrap();
+
+        let protections: Vec<(String, i32, String, i32)> = conn.prepare("SELECT media_type, media_id, title, until FROM protection ORDER BY id").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .map(|protection| protection.unwrap())
+            .collect();
+        assert_eq!(protections, [
+            ("movie".to_string(), 603, String::new(), 1893456000),
+            ("serie".to_string(), 0, "Breaking Bad".to_string(), 0)
+        ]);
+    }
+
+    #[test]
+    fn import_saves_nothing_if_a_line_is_invalid() {
+        let conn = connection();
+        let path = import_file("invalid", "\
+media_type,media_id,title,until,protected_by,reason
+movie,603,,,,
+book,1,,,,
+");
+
+        assert!(Protection::import(&conn, &path).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        let quantity: i32 = conn.query_row("SELECT COUNT(*) FROM protection", [], |row| row.get(0)).unwrap();
+        assert_eq!(quantity, 0);
+    }
+}
@@ -1,14 +1,25 @@
-use super::{disk::Disk, movie::Movie};
+use std::collections::HashMap;
+
+use super::{disk::{Disk, DiskSpace}, movie::Movie};
+use super::backend::MediaManager;
+use super::http_client::{ApiKey, HttpClient};
 use super::path::RadarrPath;
-use reqwest::blocking::Client;
+use super::service_config::ServiceConfig;
 use serde::Deserialize;
-use rusqlite::{Connection, Result, params};
+use rusqlite::{Connection, params};
+
+use crate::error::{Error, Result};
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct RootFolder {
-    path: String,
-    freeSpace: u64,
-    id: i32
+    path: String
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    id: i64,
+    label: String
 }
 
 #[derive(Debug)]
@@ -20,61 +31,64 @@ pub struct Radarr {
 }
 
 impl Radarr {
-    pub fn new(conn: &Connection, url: &str, api_key: &str, is4k: bool) -> Self {
-        conn.execute("REPLACE INTO radarr (url, api_key, is4k) VALUES (?, ?, ?)", params![url, api_key, is4k]).unwrap();
-
-        Radarr {
-            id: i32::from(conn.last_insert_rowid() as i32),
-            url: url.to_string(),
-            api_key: api_key.to_string(),
-            is4k: is4k
-        }
+    // register an instance discovered from a request manager, updated in place when already known
+    pub fn new(conn: &Connection, discovered_by: &str, server_id: i64, url: &str, api_key: &str, is4k: bool) -> Result<Self> {
+        let config = ServiceConfig::discover(conn, "radarr", discovered_by, server_id, url, api_key, is4k)?;
+
+        Ok(Radarr {
+            id: config.id,
+            url: config.url,
+            api_key: config.api_key,
+            is4k: config.is4k
+        })
     }
 
-    fn reqwest_get(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let client = Client::new();
-        let response = client.get(url).header("X-API-KEY", &self.api_key).send()?;
-        
-        if !response.status().is_success() {
-            return Err(format!("Request failed: {}", response.status()).into());
-        }
+    fn client(&self) -> HttpClient {
+        HttpClient::new(&self.url, &self.api_key, ApiKey::Header("X-Api-Key"))
+    }
+
+    pub fn get_id(&self) -> i32 {
+        self.id
+    }
 
-        response.text().map_err(|err| err.into())
+    pub fn get_url(&self) -> &str {
+        &self.url
     }
 
-    pub fn populate_paths(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-        let url = format!("{}{}", self.url, "/api/v3/rootfolder");
-        let body = self.reqwest_get(url.as_str())?;
+    pub fn populate_paths(&self, conn: &Connection) -> Result<()> {
+        let body = self.client().get("/api/v3/rootfolder")?;
 
         let root_folders: Vec<RootFolder> = serde_json::from_str(&body)?;
-        let root_folders_iter = root_folders.iter();
+
+        let body = self.client().get("/api/v3/diskspace")?;
+        let disk_spaces: Vec<DiskSpace> = serde_json::from_str(&body)?;
 
         let mut paths: Vec<RadarrPath> = Vec::new();
 
-        for root_folder in root_folders_iter {
-            let disk = match Disk::get_by_free_space(root_folder.freeSpace) {
-                Ok(disk) => disk,
-                Err(_) => Disk::new(&conn, root_folder.freeSpace)
-            };
-            paths.push(RadarrPath::new(&conn, self.id, &root_folder.path, disk.get_id()));
+        for root_folder in root_folders.iter() {
+            // a root folder on no known mount point is kept without disk, it is left out of the disk pressure
+            let disk = Disk::from_root_folder(conn, &root_folder.path, &disk_spaces)?;
+            if disk.is_none() {
+                println!("No disk found for the root folder {}", root_folder.path);
+            }
+            paths.push(RadarrPath::new(conn, self.id, &root_folder.path, disk.map(|disk| disk.get_id()))?);
         };
 
         Ok(())
     }
 
+    // get every radarr instance
     pub fn get_all(conn: &Connection) -> Result<Vec<Radarr>> {
         let mut radarrs: Vec<Radarr> = Vec::new();
 
         let mut stmt = conn.prepare("SELECT id, url, api_key, is4k FROM radarr")?;
         let radarrs_iter = stmt.query_map([], |row| {
-            let mut radarr = Radarr {
+            Ok(Radarr {
                 id: row.get(0)?,
                 url: row.get(1)?,
                 api_key: row.get(2)?,
                 is4k: row.get(3)?
-            };
-            radarr.populate_paths(&conn).unwrap();
-            Ok(radarr)
+            })
         })?;
         for radarr in radarrs_iter {
             radarrs.push(radarr?)
@@ -83,26 +97,79 @@ impl Radarr {
         Ok(radarrs)
     }
 
-    pub fn get_all_movies(&self) -> Result<Vec<Movie>, Box<dyn std::error::Error>> {
+    // get the radarr instance owning a root folder
+    pub fn get_by_path(conn: &Connection, path: &str, is4k: bool) -> Result<Radarr> {
+        let mut stmt = conn.prepare("
+            SELECT radarr.id, radarr.url, radarr.api_key, radarr.is4k
+            FROM radarr
+            INNER JOIN radarr_path ON radarr_path.radarr_id = radarr.id
+            WHERE radarr_path.path = ? AND radarr.is4k = ?
+        ")?;
+
+        let mut radarr_iter = stmt.query_map(params![path, is4k], |row| {
+            Ok(Radarr {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                api_key: row.get(2)?,
+                is4k: row.get(3)?
+            })
+        })?;
+
+        if let Some(result) = radarr_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Radarr for the path {}", path)))
+        }
+    }
+
+    // get the radarr internal id of a movie from his tmdb id
+    pub fn get_movie_id(&self, tmdb_id: i32) -> Result<i64> {
+        let response = self.client().get(&format!("/api/v3/movie?tmdbId={}", tmdb_id))?;
+        let json: serde_json::Value = serde_json::from_str(&response)?;
+
+        match json[0]["id"].as_i64() {
+            Some(id) => Ok(id),
+            None => Err(Error::NotFound(format!("Movie {} in radarr {}", tmdb_id, self.url)))
+        }
+    }
+
+    // delete a movie and his files from radarr
+    pub fn delete_movie(&self, tmdb_id: i32, add_import_exclusion: bool) -> Result<()> {
+        let id = self.get_movie_id(tmdb_id)?;
+        self.client().delete(&format!("/api/v3/movie/{}?deleteFiles=true&addImportExclusion={}", id, add_import_exclusion))
+    }
+
+    // get the names of the radarr tags by id
+    pub fn get_tags(&self) -> Result<HashMap<i64, String>> {
+        let response = self.client().get("/api/v3/tag")?;
+        let tags: Vec<Tag> = serde_json::from_str(&response)?;
+
+        Ok(tags.into_iter().map(|tag| (tag.id, tag.label)).collect())
+    }
+
+    pub fn get_all_movies(&self) -> Result<Vec<Movie>> {
         let mut movies: Vec<Movie> = Vec::new();
-        let url = format!("{}/api/v3/movie", self.url);
-        let response = self.reqwest_get(url.as_str())?;
+        let response = self.client().get("/api/v3/movie")?;
         let json: serde_json::Value = serde_json::from_str(&response)?;
+        let tags = self.get_tags()?;
 
-        let movies_json = json.as_array().unwrap();
+        let movies_json = json.as_array().ok_or_else(|| Error::missing_field("movies", "radarr movies"))?;
         for movie_json in movies_json {
-            if movie_json["tmdbId"].is_null() || movie_json["hasFile"].as_bool().unwrap() == false {
+            if movie_json["tmdbId"].is_null() || !movie_json["hasFile"].as_bool().unwrap_or(false) {
                 continue;
             }
-            movies.push(Movie::from_radarr_json(movie_json, self.is4k));
+            match Movie::from_radarr_json(movie_json, self.is4k, &tags) {
+                Ok(movie) => movies.push(movie),
+                Err(err) => println!("Skipping radarr movie : {}", err)
+            };
         }
 
         Ok(movies)
     }
 
-    pub fn update_db_movies(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn update_db_movies(&self, conn: &Connection) -> Result<()> {
         let radarr_movies = self.get_all_movies()?;
-        let mut db_movies = Movie::get_all(&conn)?;
+        let mut db_movies = Movie::get_all(conn)?;
 
         let mut quantity_created = 0;
         let mut quantity_updated = 0;
@@ -111,31 +178,58 @@ impl Radarr {
             if let Some(db_movie) = db_movies.iter_mut().find(|db_movie| db_movie.tmdb_id == radarr_movie.tmdb_id) {
                 let mut changed = false;
 
+                // the movie has been downloaded again since it was deleted
+                if db_movie.deleted {
+                    db_movie.deleted = false;
+                    db_movie.last_view = chrono::Utc::now().timestamp() as i32;
+                    db_movie.last_view_source = "radarr".to_string();
+                    db_movie.added = db_movie.last_view;
+                    changed = true;
+                }
+
+                // keep the first known added date
+                if db_movie.added == 0 && radarr_movie.added != 0 {
+                    db_movie.added = radarr_movie.added;
+                    changed = true;
+                }
+
                 // update name if changed
                 if db_movie.name != radarr_movie.name {
                     db_movie.name = radarr_movie.name;
                     changed = true;
                 }
 
-                // if is4k = true, update path_4k if changed
-                if self.is4k && db_movie.path_4k != radarr_movie.path_4k {
+                // if is4k = true, update path_4k and size_4k if changed
+                if self.is4k && (db_movie.path_4k != radarr_movie.path_4k || db_movie.size_4k != radarr_movie.size_4k) {
                     db_movie.path_4k = radarr_movie.path_4k;
+                    db_movie.size_4k = radarr_movie.size_4k;
                     changed = true;
                 }
 
-                // if is4k = false, update path_hd if changed
-                if !self.is4k && db_movie.path_hd != radarr_movie.path_hd {
+                // if is4k = false, update path_hd and size_hd if changed
+                if !self.is4k && (db_movie.path_hd != radarr_movie.path_hd || db_movie.size_hd != radarr_movie.size_hd) {
                     db_movie.path_hd = radarr_movie.path_hd;
+                    db_movie.size_hd = radarr_movie.size_hd;
+                    changed = true;
+                }
+
+                // update the tags of the copy if changed
+                if self.is4k && db_movie.tags_4k != radarr_movie.tags_4k {
+                    db_movie.tags_4k = radarr_movie.tags_4k;
+                    changed = true;
+                }
+                if !self.is4k && db_movie.tags_hd != radarr_movie.tags_hd {
+                    db_movie.tags_hd = radarr_movie.tags_hd;
                     changed = true;
                 }
 
                 // if changed, update db
                 if changed {
-                    db_movie.save(&conn)?;
+                    db_movie.save(conn)?;
                     quantity_updated += 1;
                 }
             } else {
-                radarr_movie.save(&conn)?;
+                radarr_movie.save(conn)?;
                 quantity_created += 1;
             }
         }
@@ -144,4 +238,18 @@ impl Radarr {
         println!("Updated movies : {}", quantity_updated);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl MediaManager for Radarr {
+    fn get_name(&self) -> &str {
+        "radarr"
+    }
+
+    fn populate_paths(&self, conn: &Connection) -> Result<()> {
+        Radarr::populate_paths(self, conn)
+    }
+
+    fn update_db_medias(&self, conn: &Connection) -> Result<()> {
+        self.update_db_movies(conn)
+    }
+}
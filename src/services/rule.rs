@@ -0,0 +1,330 @@
+use rusqlite::{Connection, Row};
+
+use super::{movie::Movie, serie::Serie};
+
+use crate::error::Result;
+
+// default delay without view before a media is deleted when no rule is configured
+const DEFAULT_DELAY_DAYS: i64 = 90;
+
+// a condition compares a media field to a value, e.g. "last_view_days > 90"
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub field: String,
+    pub operator: String,
+    pub value: String
+}
+
+// a rule selects the medias matching all (AND) or any (OR) of its conditions
+#[derive(Debug)]
+pub struct Rule {
+    pub id: i32,
+    pub name: String,
+    pub media_type: String,
+    pub operator: String,
+    pub conditions: Vec<Condition>
+}
+
+// values of the HD or 4K copy of a movie or a serie the conditions are evaluated against
+struct Media<'a> {
+    media_type: &'a str,
+    last_view: i32,
+    added: i32,
+    // "hd" or "4k"
+    quality: &'a str,
+    path: &'a str,
+    protected: bool,
+    size: i64,
+    tags: &'a [String]
+}
+
+impl Condition {
+    fn from_row(row: &Row) -> rusqlite::Result<Condition> {
+        Ok(Condition {
+            field: row.get(0)?,
+            operator: row.get(1)?,
+            value: row.get(2)?
+        })
+    }
+
+    fn matches(&self, media: &Media, now: i64) -> bool {
+        let day = 60 * 60 * 24;
+
+        match self.field.as_str() {
+            "last_view_days" => self.compare_number(((now - media.last_view as i64) / day) as f64),
+            // medias without a known added date never match
+            "added_days" => media.added > 0 && self.compare_number(((now - media.added as i64) / day) as f64),
+            "size_gb" => self.compare_number(media.size as f64 / 1_000_000_000.0),
+            "media_type" => self.compare_values(&[media.media_type]),
+            "quality" => self.compare_values(&[media.quality]),
+            "root_folder" => self.compare_values(&[media.path]),
+            "protected" => self.compare_values(&[if media.protected { "true" } else { "false" }]),
+            "tag" => self.compare_values(media.tags),
+            _ => {
+                println!("Unknown rule condition field : {}", self.field);
+                false
+            }
+        }
+    }
+
+    fn compare_number(&self, actual: f64) -> bool {
+        let expected = match self.value.trim().parse::<f64>() {
+            Ok(expected) => expected,
+            Err(_) => return false
+        };
+
+        match self.operator.as_str() {
+            ">" => actual > expected,
+            ">=" => actual >= expected,
+            "<" => actual < expected,
+            "<=" => actual <= expected,
+            "=" => actual == expected,
+            "!=" => actual != expected,
+            _ => false
+        }
+    }
+
+    // "=" matches if one of the media values is the expected one, "!=" if none is
+    fn compare_values<S: AsRef<str>>(&self, actual: &[S]) -> bool {
+        let found = actual.iter().any(|value| value.as_ref().eq_ignore_ascii_case(self.value.trim()));
+
+        match self.operator.as_str() {
+            "=" => found,
+            "!=" => !found,
+            _ => false
+        }
+    }
+}
+
+impl Rule {
+    // get enabled rules from database, or the default delay rule if none is configured
+    pub fn get_all(conn: &Connection) -> Result<Vec<Rule>> {
+        let mut stmt = conn.prepare("
+            SELECT id, name, media_type, operator
+            FROM rule
+            WHERE enabled = 1
+        ")?;
+
+        let rule_iter = stmt.query_map([], |row| {
+            Ok(Rule {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                media_type: row.get(2)?,
+                operator: row.get(3)?,
+                conditions: Vec::new()
+            })
+        })?;
+
+        let mut rules = Vec::new();
+        for rule in rule_iter {
+            let mut rule = rule?;
+
+            let mut stmt = conn.prepare("
+                SELECT field, operator, value
+                FROM rule_condition
+                WHERE rule_id = ?
+            ")?;
+            for condition in stmt.query_map([rule.id], Condition::from_row)? {
+                rule.conditions.push(condition?);
+            }
+
+            rules.push(rule);
+        }
+
+        if rules.is_empty() {
+            rules.push(Rule::default_rule());
+        }
+
+        Ok(rules)
+    }
+
+    // select every media not viewed since DELETION_DELAY_DAYS days
+    fn default_rule() -> Rule {
+        let delay = std::env::var("DELETION_DELAY_DAYS").ok()
+            .and_then(|delay| delay.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_DELAY_DAYS);
+
+        Rule {
+            id: 0,
+            name: format!("Not viewed since {} days", delay),
+            media_type: String::new(),
+            operator: "AND".to_string(),
+            conditions: vec![Condition {
+                field: "last_view_days".to_string(),
+                operator: ">".to_string(),
+                value: delay.to_string()
+            }]
+        }
+    }
+
+    // the weakest last_view_days and added_days minimums of the rules, a media not matching them is too recent to be selected by any of them
+    pub fn age_floor(rules: &[Rule]) -> Rule {
+        let mut conditions = Vec::new();
+        for field in ["last_view_days", "added_days"] {
+            let weakest = rules.iter()
+                .flat_map(|rule| rule.conditions.iter())
+                .filter(|condition| condition.field == field && (condition.operator == ">" || condition.operator == ">="))
+                .filter_map(|condition| condition.value.trim().parse::<f64>().ok().map(|days| (days, condition)))
+                .min_by(|(days, _), (other_days, _)| days.total_cmp(other_days));
+            if let Some((_, condition)) = weakest {
+                conditions.push(condition.clone());
+            }
+        }
+
+        Rule {
+            id: 0,
+            name: "Age floor".to_string(),
+            media_type: String::new(),
+            operator: "AND".to_string(),
+            conditions
+        }
+    }
+
+    fn matches(&self, media: &Media) -> bool {
+        if !self.media_type.is_empty() && self.media_type != media.media_type {
+            return false;
+        }
+
+        // a rule without condition would select every media
+        if self.conditions.is_empty() {
+            return false;
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        if self.operator.eq_ignore_ascii_case("OR") {
+            self.conditions.iter().any(|condition| condition.matches(media, now))
+        } else {
+            self.conditions.iter().all(|condition| condition.matches(media, now))
+        }
+    }
+
+    // check the HD or 4K copy of a movie
+    pub fn matches_movie(&self, movie: &Movie, is4k: bool) -> bool {
+        self.matches(&Media {
+            media_type: "movie",
+            last_view: movie.last_view,
+            added: movie.added,
+            quality: if is4k { "4k" } else { "hd" },
+            path: if is4k { &movie.path_4k } else { &movie.path_hd },
+            protected: movie.protected,
+            size: if is4k { movie.size_4k } else { movie.size_hd },
+            tags: if is4k { &movie.tags_4k } else { &movie.tags_hd }
+        })
+    }
+
+    // check the HD or 4K copy of a serie
+    pub fn matches_serie(&self, serie: &Serie, is4k: bool) -> bool {
+        self.matches(&Media {
+            media_type: "serie",
+            last_view: serie.last_view,
+            added: serie.added,
+            quality: if is4k { "4k" } else { "hd" },
+            path: if is4k { &serie.path_4k } else { &serie.path_hd },
+            protected: serie.protected,
+            size: if is4k { serie.size_4k } else { serie.size_hd },
+            tags: if is4k { &serie.tags_4k } else { &serie.tags_hd }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i32 = 60 * 60 * 24;
+
+    // a movie viewed and added days ago, with a HD copy and a 4K copy
+    fn movie(last_view_days: i32, added_days: i32) -> Movie {
+        let now = chrono::Utc::now().timestamp() as i32;
+        Movie {
+            tmdb_id: 603,
+            name: "The Matrix".to_string(),
+            path_hd: "/movies".to_string(),
+            path_4k: "/movies4k".to_string(),
+            rating_key: String::new(),
+            last_view: now - last_view_days * DAY,
+            last_view_source: "tautulli".to_string(),
+            protected: false,
+            deleted: false,
+            added: now - added_days * DAY,
+            size_hd: 2_000_000_000,
+            size_4k: 8_000_000_000,
+            tags_hd: vec!["anime".to_string()],
+            tags_4k: Vec::new()
+        }
+    }
+
+    fn rule(media_type: &str, operator: &str, conditions: &[(&str, &str, &str)]) -> Rule {
+        Rule {
+            id: 1,
+            name: "Test".to_string(),
+            media_type: media_type.to_string(),
+            operator: operator.to_string(),
+            conditions: conditions.iter().map(|(field, operator, value)| Condition {
+                field: field.to_string(),
+                operator: operator.to_string(),
+                value: value.to_string()
+            }).collect()
+        }
+    }
+
+    #[test]
+    fn and_rules_need_every_condition() {
+        let rule = rule("", "AND", &[("last_view_days", ">", "30"), ("added_days", ">", "60")]);
+        assert!(rule.matches_movie(&movie(40, 100), false));
+        assert!(!rule.matches_movie(&movie(40, 50), false));
+        assert!(!rule.matches_movie(&movie(10, 100), false));
+    }
+
+    #[test]
+    fn or_rules_need_one_condition() {
+        let rule = rule("", "or", &[("last_view_days", ">", "30"), ("added_days", ">", "60")]);
+        assert!(rule.matches_movie(&movie(40, 50), false));
+        assert!(rule.matches_movie(&movie(10, 100), false));
+        assert!(!rule.matches_movie(&movie(10, 50), false));
+    }
+
+    #[test]
+    fn empty_rules_never_match() {
+        assert!(!rule("", "AND", &[]).matches_movie(&movie(1000, 1000), false));
+        assert!(!rule("", "OR", &[]).matches_movie(&movie(1000, 1000), true));
+    }
+
+    #[test]
+    fn rules_only_match_their_media_type() {
+        let rule = rule("serie", "AND", &[("last_view_days", ">", "30")]);
+        assert!(!rule.matches_movie(&movie(40, 40), false));
+    }
+
+    #[test]
+    fn copies_are_matched_on_their_own_quality_and_size() {
+        let old_4k = rule("", "AND", &[("quality", "=", "4k"), ("last_view_days", ">", "30")]);
+        assert!(old_4k.matches_movie(&movie(40, 40), true));
+        assert!(!old_4k.matches_movie(&movie(40, 40), false));
+
+        let large = rule("", "AND", &[("size_gb", ">=", "5")]);
+        assert!(large.matches_movie(&movie(0, 0), true));
+        assert!(!large.matches_movie(&movie(0, 0), false));
+
+        let root_folder = rule("", "AND", &[("root_folder", "!=", "/movies4k")]);
+        assert!(root_folder.matches_movie(&movie(0, 0), false));
+        assert!(!root_folder.matches_movie(&movie(0, 0), true));
+
+        let tagged = rule("", "AND", &[("tag", "=", "Anime")]);
+        assert!(tagged.matches_movie(&movie(0, 0), false));
+        assert!(!tagged.matches_movie(&movie(0, 0), true));
+    }
+
+    #[test]
+    fn the_age_floor_keeps_the_weakest_minimums() {
+        let rules = [
+            rule("movie", "AND", &[("last_view_days", ">", "90"), ("quality", "=", "4k")]),
+            rule("serie", "OR", &[("last_view_days", ">=", "30"), ("added_days", ">", "60")])
+        ];
+        let floor = Rule::age_floor(&rules);
+        assert_eq!(floor.conditions.len(), 2);
+        assert_eq!(floor.conditions[0].value, "30");
+        assert_eq!(floor.conditions[1].value, "60");
+        assert!(Rule::age_floor(&[rule("", "AND", &[("size_gb", ">", "1")])]).conditions.is_empty());
+    }
+}
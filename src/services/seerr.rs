@@ -0,0 +1,248 @@
+use rusqlite::Connection;
+
+use super::http_client::{ApiKey, HttpClient};
+use super::{media::convert_date_to_timestamp, movie::Movie, radarr::Radarr, serie::Serie, service_config::ServiceConfig, sonarr::Sonarr};
+
+use crate::error::{Error, Result};
+
+// a request manager speaking the overseerr api, shared by overseerr and its jellyseerr fork
+#[derive(Debug)]
+pub struct Seerr {
+    // the name of the service, also its table
+    name: &'static str,
+    // the field holding the id of a media on the media server, the 4k one is suffixed with 4k
+    media_id_field: &'static str,
+    id: i32,
+    url: String,
+    api_key: String
+}
+
+impl Seerr {
+    // get all instances of a request manager from database, the name of the service being its table
+    pub fn get_all(conn: &Connection, name: &'static str, media_id_field: &'static str) -> Result<Vec<Seerr>> {
+        let mut stmt = conn.prepare(&format!("
+            SELECT id, url, api_key
+            FROM {}
+        ", name))?;
+
+        let seerr_iter = stmt.query_map([], |row| {
+            Ok(Seerr {
+                name,
+                media_id_field,
+                id: row.get(0)?,
+                url: row.get(1)?,
+                api_key: row.get(2)?
+            })
+        })?;
+
+        let mut seerrs = Vec::new();
+        for seerr in seerr_iter {
+            seerrs.push(seerr?);
+        }
+
+        Ok(seerrs)
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    fn client(&self) -> HttpClient {
+        HttpClient::new(&self.url, &self.api_key, ApiKey::Header("x-api-key"))
+    }
+
+    // get the medias of a type ("movie" or "tv") known by the media server
+    pub fn get_medias(&self, media_type: &str) -> Result<Vec<serde_json::Value>> {
+        let response = self.client().get("/api/v1/Media?take=5000")?;
+        let json: serde_json::Value = serde_json::from_str(&response)?;
+
+        let results = json["results"].as_array().ok_or_else(|| Error::missing_field("results", &format!("{} medias", self.name)))?;
+        let media_id_field_4k = format!("{}4k", self.media_id_field);
+        let medias = results.iter().filter(|media| {
+            // if the media id is null, the media is not in the media server, so skip it
+            !(media[self.media_id_field].is_null() && media[media_id_field_4k.as_str()].is_null()) && media["mediaType"].as_str() == Some(media_type)
+        }).cloned().collect();
+
+        Ok(medias)
+    }
+
+    // get the request date of a media as a unix timestamp
+    fn get_created_at(&self, media: &serde_json::Value) -> Result<i32> {
+        let created_at = media["createdAt"].as_str().ok_or_else(|| Error::missing_field("createdAt", &format!("{} media", self.name)))?;
+        match convert_date_to_timestamp(created_at) {
+            0 => Err(Error::Invalid(format!("Invalid date {}", created_at))),
+            created_at => Ok(created_at)
+        }
+    }
+
+    fn to_movie(&self, media: &serde_json::Value) -> Result<Movie> {
+        let created_at = self.get_created_at(media)?;
+
+        Ok(Movie {
+            tmdb_id: media["tmdbId"].as_i64().ok_or_else(|| Error::missing_field("tmdbId", &format!("{} media", self.name)))? as i32,
+            name: String::new(),
+            path_hd: String::new(),
+            path_4k: String::new(),
+            rating_key: media["ratingKey"].as_str().unwrap_or("").to_string(),
+            last_view: created_at,
+            last_view_source: self.name.to_string(),
+            protected: false,
+            deleted: false,
+            added: created_at,
+            size_hd: 0,
+            size_4k: 0,
+            tags_hd: Vec::new(),
+            tags_4k: Vec::new()
+        })
+    }
+
+    fn to_serie(&self, media: &serde_json::Value) -> Result<Serie> {
+        let created_at = self.get_created_at(media)?;
+
+        Ok(Serie {
+            tvdb_id: media["tvdbId"].as_i64().ok_or_else(|| Error::missing_field("tvdbId", &format!("{} media", self.name)))? as i32,
+            name: String::new(),
+            path_hd: String::new(),
+            path_4k: String::new(),
+            rating_key: media["ratingKey"].as_str().unwrap_or("").to_string(),
+            last_view: created_at,
+            last_view_source: self.name.to_string(),
+            protected: false,
+            deleted: false,
+            added: created_at,
+            size_hd: 0,
+            size_4k: 0,
+            tags_hd: Vec::new(),
+            tags_4k: Vec::new()
+        })
+    }
+
+    // get all movies from the request manager, the malformed ones are skipped
+    pub fn get_all_movies(&self) -> Result<Vec<Movie>> {
+        let mut movies: Vec<Movie> = Vec::new();
+
+        for media in self.get_medias("movie")? {
+            match self.to_movie(&media) {
+                Ok(movie) => movies.push(movie),
+                Err(err) => println!("Skipping {} movie : {}", self.name, err)
+            };
+        }
+
+        Ok(movies)
+    }
+
+    pub fn get_all_series(&self) -> Result<Vec<Serie>> {
+        let mut series: Vec<Serie> = Vec::new();
+
+        for media in self.get_medias("tv")? {
+            match self.to_serie(&media) {
+                Ok(serie) => series.push(serie),
+                Err(err) => println!("Skipping {} serie : {}", self.name, err)
+            };
+        }
+
+        Ok(series)
+    }
+
+    // get the requested movies and insert missing one into the database
+    pub fn update_db_movies(&self, conn: &Connection) -> Result<()> {
+        let db_movies = Movie::get_all(conn)?;
+        let seerr_movies = self.get_all_movies()?;
+
+        let mut quantity_added = 0;
+
+        for seerr_movie in seerr_movies {
+            if !db_movies.contains(&seerr_movie) {
+                seerr_movie.save(conn)?;
+                quantity_added += 1;
+            }
+        }
+
+        println!("Added movies : {}", quantity_added);
+        Ok(())
+    }
+
+    // get the requested series and insert missing one into the database
+    pub fn update_db_series(&self, conn: &Connection) -> Result<()> {
+        let db_series = Serie::get_all(conn)?;
+        let seerr_series = self.get_all_series()?;
+
+        let mut quantity_added = 0;
+
+        for seerr_serie in seerr_series {
+            if !db_series.contains(&seerr_serie) {
+                seerr_serie.save(conn)?;
+                quantity_added += 1;
+            }
+        }
+
+        println!("Added series : {}", quantity_added);
+        Ok(())
+    }
+
+    // get radarrs configuration from the request manager
+    fn get_radarrs(&self, conn: &Connection) -> Result<Vec<Radarr>> {
+        let mut radarrs: Vec<Radarr> = Vec::new();
+
+        let response = self.client().get("/api/v1/settings/radarr")?;
+        let json: serde_json::Value = serde_json::from_str(&response)?;
+
+        // the instances are known by their id in the settings, those removed from them are deleted
+        let discovered_by = format!("{} {}", self.name, self.id);
+        let mut server_ids = Vec::new();
+        for radarr in json.as_array().ok_or_else(|| Error::missing_field("radarrs", &format!("{} settings", self.name)))? {
+            server_ids.extend(radarr["id"].as_i64());
+            let radarr = self.get_instance(radarr).and_then(|(server_id, url, api_key, is4k)| Radarr::new(conn, &discovered_by, server_id, url, api_key, is4k));
+            match radarr {
+                Ok(radarr) => radarrs.push(radarr),
+                Err(err) => println!("Skipping {} radarr : {}", self.name, err)
+            };
+        }
+        ServiceConfig::delete_undiscovered(conn, "radarr", &discovered_by, &server_ids)?;
+
+        Ok(radarrs)
+    }
+
+    // get sonarrs configuration from the request manager
+    fn get_sonarrs(&self, conn: &Connection) -> Result<Vec<Sonarr>> {
+        let mut sonarrs: Vec<Sonarr> = Vec::new();
+
+        let response = self.client().get("/api/v1/settings/sonarr")?;
+        let json: serde_json::Value = serde_json::from_str(&response)?;
+
+        // the instances are known by their id in the settings, those removed from them are deleted
+        let discovered_by = format!("{} {}", self.name, self.id);
+        let mut server_ids = Vec::new();
+        for sonarr in json.as_array().ok_or_else(|| Error::missing_field("sonarrs", &format!("{} settings", self.name)))? {
+            server_ids.extend(sonarr["id"].as_i64());
+            let sonarr = self.get_instance(sonarr).and_then(|(server_id, url, api_key, is4k)| Sonarr::new(conn, &discovered_by, server_id, url, api_key, is4k));
+            match sonarr {
+                Ok(sonarr) => sonarrs.push(sonarr),
+                Err(err) => println!("Skipping {} sonarr : {}", self.name, err)
+            };
+        }
+        ServiceConfig::delete_undiscovered(conn, "sonarr", &discovered_by, &server_ids)?;
+
+        Ok(sonarrs)
+    }
+
+    // register both media managers, even if the other one fails
+    pub fn discover_media_managers(&self, conn: &Connection) -> Result<()> {
+        let radarrs = self.get_radarrs(conn);
+        let sonarrs = self.get_sonarrs(conn);
+        radarrs?;
+        sonarrs?;
+        Ok(())
+    }
+
+    // get the id, url, api key and quality of a radarr or sonarr from the settings
+    fn get_instance<'a>(&self, instance: &'a serde_json::Value) -> Result<(i64, &'a str, &'a str, bool)> {
+        let context = format!("{} settings", self.name);
+        Ok((
+            instance["id"].as_i64().ok_or_else(|| Error::missing_field("id", &context))?,
+            instance["externalUrl"].as_str().ok_or_else(|| Error::missing_field("externalUrl", &context))?,
+            instance["apiKey"].as_str().ok_or_else(|| Error::missing_field("apiKey", &context))?,
+            instance["is4k"].as_bool().ok_or_else(|| Error::missing_field("is4k", &context))?
+        ))
+    }
+}
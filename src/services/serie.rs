@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use rusqlite::{Connection, Row, params};
+use serde::Serialize;
+
+use super::{deletion::Deletion, disk::Disk, media::{convert_date_to_timestamp, split_tags}, path::{GetDisk, SonarrPath}, rule::Rule, sonarr::Sonarr};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Serie {
+    pub tvdb_id: i32,
+    pub name: String,
+    pub path_hd: String,
+    pub path_4k: String,
+    pub rating_key: String,
+    pub last_view: i32,
+    // where the last view comes from (overseerr, jellyseerr, radarr, sonarr, tautulli or jellyfin)
+    pub last_view_source: String,
+    pub protected: bool,
+    pub deleted: bool,
+    pub added: i32,
+    pub size_hd: i64,
+    pub size_4k: i64,
+    // radarr/sonarr tag names of each copy
+    pub tags_hd: Vec<String>,
+    pub tags_4k: Vec<String>
+}
+
+impl Serie {
+    fn from_row(row: &Row) -> rusqlite::Result<Serie> {
+        Ok(Serie {
+            tvdb_id: row.get(0)?,
+            name: row.get(1)?,
+            path_hd: row.get(2)?,
+            path_4k: row.get(3)?,
+            rating_key: row.get(4)?,
+            last_view: row.get(5)?,
+            last_view_source: row.get(6)?,
+            protected: row.get(7)?,
+            deleted: row.get(8)?,
+            added: row.get(9)?,
+            size_hd: row.get(10)?,
+            size_4k: row.get(11)?,
+            tags_hd: split_tags(row.get(12)?),
+            tags_4k: split_tags(row.get(13)?)
+        })
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        conn.execute("
+            REPLACE INTO serie (tvdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ", params![
+            &self.tvdb_id,
+            &self.name,
+            &self.path_hd,
+            &self.path_4k,
+            &self.rating_key,
+            &self.last_view,
+            &self.last_view_source,
+            &self.protected,
+            &self.deleted,
+            &self.added,
+            &self.size_hd,
+            &self.size_4k,
+            &self.tags_hd.join(","),
+            &self.tags_4k.join(","),
+        ])?;
+        Ok(())
+    }
+
+    // create static function that returns all database series
+    pub fn get_all(conn: &Connection) -> Result<Vec<Serie>> {
+        let mut stmt = conn.prepare("
+            SELECT tvdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
+            FROM serie
+        ")?;
+
+        let series_iter = stmt.query_map([], Serie::from_row)?;
+
+        let mut series = Vec::new();
+        for serie in series_iter {
+            series.push(serie?);
+        }
+
+        Ok(series)
+    }
+
+    // create from_sonarr_json function
+    pub fn from_sonarr_json(json: &serde_json::Value, is4k: bool, tags: &HashMap<i64, String>) -> Result<Self> {
+        // until a view is found, the serie is considered viewed when added
+        let added = convert_date_to_timestamp(json["added"].as_str().unwrap_or(""));
+        let mut serie = Serie {
+            tvdb_id: json["tvdbId"].as_i64().ok_or_else(|| Error::missing_field("tvdbId", "sonarr serie"))? as i32,
+            name: json["title"].as_str().ok_or_else(|| Error::missing_field("title", "sonarr serie"))?.to_string(),
+            path_hd: String::new(),
+            path_4k: String::new(),
+            rating_key: String::new(),
+            last_view: added,
+            last_view_source: "sonarr".to_string(),
+            protected: false,
+            deleted: false,
+            added,
+            size_hd: 0,
+            size_4k: 0,
+            tags_hd: Vec::new(),
+            tags_4k: Vec::new()
+        };
+        // resolve the tag ids to their names
+        let tag_names = json["tags"].as_array().map(|ids| {
+            ids.iter().filter_map(|id| id.as_i64().and_then(|id| tags.get(&id)).cloned()).collect()
+        }).unwrap_or_default();
+        if is4k {
+            serie.tags_4k = tag_names;
+            serie.path_4k = json["rootFolderPath"].as_str().ok_or_else(|| Error::missing_field("rootFolderPath", "sonarr serie"))?.to_string();
+            serie.size_4k = json["statistics"]["sizeOnDisk"].as_i64().unwrap_or(0);
+        } else {
+            serie.tags_hd = tag_names;
+            serie.path_hd = json["rootFolderPath"].as_str().ok_or_else(|| Error::missing_field("rootFolderPath", "sonarr serie"))?.to_string();
+            serie.size_hd = json["statistics"]["sizeOnDisk"].as_i64().unwrap_or(0);
+        }
+        Ok(serie)
+    }
+
+    pub fn get_by_tvdb_id(conn: &Connection, tvdb_id: i32) -> Result<Serie> {
+        let mut stmt = conn.prepare("
+            SELECT tvdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
+            FROM serie
+            WHERE tvdb_id = ?
+        ")?;
+
+        let mut serie_iter = stmt.query_map([tvdb_id], Serie::from_row)?;
+
+        if let Some(result) = serie_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Serie {}", tvdb_id)))
+        }
+    }
+
+    // get the copies of the unprotected series matching at least one of the rules, with true if the copy is the 4K one
+    pub fn get_series_to_delete(conn: &Connection, rules: &[Rule]) -> Result<Vec<(Serie, bool)>> {
+        let mut stmt = conn.prepare("
+            SELECT tvdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
+            FROM serie
+            WHERE deleted = 0 AND protected = 0
+        ")?;
+
+        let serie_iter = stmt.query_map([], Serie::from_row)?;
+
+        let mut series = Vec::new();
+        for result in serie_iter {
+            let serie = result?;
+            for is4k in [false, true] {
+                let path = if is4k { &serie.path_4k } else { &serie.path_hd };
+                if !path.is_empty() && rules.iter().any(|rule| rule.matches_serie(&serie, is4k)) {
+                    series.push((serie.clone(), is4k));
+                }
+            }
+        }
+
+        Ok(series)
+    }
+
+    pub fn get_by_rating_key(conn: &Connection, rating_key: &str) -> Result<Serie> {
+        let mut stmt = conn.prepare("
+            SELECT tvdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k
+            FROM serie
+            WHERE rating_key = ?
+        ")?;
+
+        let mut serie_iter = stmt.query_map([rating_key], Serie::from_row)?;
+
+        if let Some(result) = serie_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Serie with the rating key {}", rating_key)))
+        }
+    }
+
+    // delete the HD or 4K copy of the serie from the sonarr instance owning its root folder and record the outcome
+    pub fn delete_copy(&mut self, conn: &Connection, is4k: bool) -> Result<()> {
+        let path = if is4k { self.path_4k.clone() } else { self.path_hd.clone() };
+        if path.is_empty() {
+            return Ok(());
+        }
+
+        let sonarr = Sonarr::get_by_path(conn, &path, is4k)?;
+        let result = sonarr.delete_serie(self.tvdb_id);
+        Deletion::new("serie", self.tvdb_id, &self.name, sonarr.get_id(), &path, &result).save(conn)?;
+        result?;
+
+        // only the column of the copy is updated, the other copy may have been deleted since the serie was read
+        let column = if is4k { "path_4k" } else { "path_hd" };
+        conn.execute(&format!("UPDATE serie SET {column} = '' WHERE tvdb_id = ?"), [self.tvdb_id])?;
+        // only flag the serie once it is gone from every instance, so failed ones are retried next run
+        conn.execute("UPDATE serie SET deleted = 1 WHERE tvdb_id = ? AND path_hd = '' AND path_4k = ''", [self.tvdb_id])?;
+
+        if is4k {
+            self.path_4k = String::new();
+        } else {
+            self.path_hd = String::new();
+        }
+        self.deleted = self.path_hd.is_empty() && self.path_4k.is_empty();
+        Ok(())
+    }
+
+    // get the disk storing the HD or 4K copy of the serie
+    pub fn get_disk(&self, conn: &Connection, is4k: bool) -> Result<Disk> {
+        let path = if is4k { &self.path_4k } else { &self.path_hd };
+        SonarrPath::get_by_path(conn, path, is4k)?.get_disk(conn)
+    }
+
+    // get the space that would be freed on the disk by deleting all the series stored on it
+    pub fn get_size_on_disk(conn: &Connection, disk_id: i32) -> Result<i64> {
+        let size = Serie::get_on_disk(conn, disk_id)?.iter()
+            .map(|(serie, is4k)| if *is4k { serie.size_4k } else { serie.size_hd })
+            .sum();
+
+        Ok(size)
+    }
+
+    // get series with a copy on the disk, with true if the copy is the 4K one
+    pub fn get_on_disk(conn: &Connection, disk_id: i32) -> Result<Vec<(Serie, bool)>> {
+        let mut stmt = conn.prepare("
+            SELECT tvdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k, 0
+            FROM serie
+            WHERE deleted = 0 AND path_hd IN (
+                SELECT sonarr_path.path
+                FROM sonarr_path
+                INNER JOIN sonarr ON sonarr.id = sonarr_path.sonarr_id
+                WHERE sonarr_path.disk_id = ?1 AND sonarr.is4k = 0
+            )
+            UNION ALL
+            SELECT tvdb_id, name, path_hd, path_4k, rating_key, last_view, last_view_source, protected, deleted, added, size_hd, size_4k, tags_hd, tags_4k, 1
+            FROM serie
+            WHERE deleted = 0 AND path_4k IN (
+                SELECT sonarr_path.path
+                FROM sonarr_path
+                INNER JOIN sonarr ON sonarr.id = sonarr_path.sonarr_id
+                WHERE sonarr_path.disk_id = ?1 AND sonarr.is4k = 1
+            )
+        ")?;
+
+        let serie_iter = stmt.query_map([disk_id], |row| {
+            Ok((Serie::from_row(row)?, row.get(14)?))
+        })?;
+
+        let mut series = Vec::new();
+        for result in serie_iter {
+            series.push(result?);
+        }
+
+        Ok(series)
+    }
+}
+
+// add partial_eq trait to Serie struct
+impl PartialEq for Serie {
+    fn eq(&self, other: &Self) -> bool {
+        self.tvdb_id == other.tvdb_id
+    }
+}
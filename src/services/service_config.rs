@@ -0,0 +1,297 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+
+use super::http_client::{ApiKey, HttpClient};
+
+use crate::error::{Error, Result};
+
+// every kind of service, each one stored in the table of the same name
+pub const SERVICE_KINDS: [&str; 6] = ["overseerr", "jellyseerr", "radarr", "sonarr", "jellyfin", "tautulli"];
+
+// the playback reporting plugin or the user data of every jellyfin user
+pub const JELLYFIN_ACTIVITY_SOURCES: [&str; 2] = ["playback_reporting", "user_data"];
+
+// the configuration of a service instance, whatever its kind
+#[derive(Debug, Serialize)]
+pub struct ServiceConfig {
+    pub kind: String,
+    pub id: i32,
+    pub url: String,
+    // never exposed, only sent to the service
+    #[serde(skip_serializing)]
+    pub api_key: String,
+    // only radarr and sonarr have 4K instances
+    pub is4k: bool,
+    // only jellyfin has several activity sources
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub activity_source: String
+}
+
+// the outcome of a connection test
+#[derive(Debug, Default, Serialize)]
+pub struct ConnectionTest {
+    pub reachable: bool,
+    pub authenticated: bool,
+    pub version: String,
+    pub error: String
+}
+
+impl ConnectionTest {
+    pub fn is_success(&self) -> bool {
+        self.reachable && self.authenticated
+    }
+}
+
+impl ServiceConfig {
+    pub fn new(kind: &str, url: &str, api_key: &str, is4k: bool) -> Self {
+        ServiceConfig {
+            kind: kind.to_string(),
+            id: 0,
+            url: url.trim().trim_end_matches('/').to_string(),
+            api_key: api_key.trim().to_string(),
+            is4k: is4k && ServiceConfig::has_is4k(kind),
+            activity_source: if kind == "jellyfin" { JELLYFIN_ACTIVITY_SOURCES[0].to_string() } else { String::new() }
+        }
+    }
+
+    fn check_kind(kind: &str) -> Result<()> {
+        if SERVICE_KINDS.contains(&kind) {
+            Ok(())
+        } else {
+            Err(Error::Invalid(format!("Unknown service kind : {}", kind)))
+        }
+    }
+
+    fn has_is4k(kind: &str) -> bool {
+        kind == "radarr" || kind == "sonarr"
+    }
+
+    // get every instance of a kind of service
+    pub fn get_all(conn: &Connection, kind: &str) -> Result<Vec<ServiceConfig>> {
+        ServiceConfig::check_kind(kind)?;
+
+        let query = if ServiceConfig::has_is4k(kind) {
+            format!("SELECT id, url, api_key, is4k, '' FROM {}", kind)
+        } else if kind == "jellyfin" {
+            "SELECT id, url, api_key, 0, activity_source FROM jellyfin".to_string()
+        } else {
+            format!("SELECT id, url, api_key, 0, '' FROM {}", kind)
+        };
+        let mut stmt = conn.prepare(&query)?;
+        let config_iter = stmt.query_map([], |row| {
+            Ok(ServiceConfig {
+                kind: kind.to_string(),
+                id: row.get(0)?,
+                url: row.get(1)?,
+                api_key: row.get(2)?,
+                is4k: row.get(3)?,
+                activity_source: row.get(4)?
+            })
+        })?;
+
+        let mut configs = Vec::new();
+        for config in config_iter {
+            configs.push(config?);
+        }
+
+        Ok(configs)
+    }
+
+    pub fn get_by_id(conn: &Connection, kind: &str, id: i32) -> Result<ServiceConfig> {
+        ServiceConfig::get_all(conn, kind)?.into_iter()
+            .find(|config| config.id == id)
+            .ok_or_else(|| Error::NotFound(format!("{} {}", kind, id)))
+    }
+
+    // the configuration with the given fields changed, the api key of an instance is never sent to a new url
+    pub fn update(&self, url: Option<String>, api_key: Option<String>, is4k: Option<bool>, activity_source: Option<String>) -> Result<ServiceConfig> {
+        let url = url.unwrap_or_else(|| self.url.clone());
+        let url_changed = url.trim().trim_end_matches('/') != self.url;
+        let api_key = match api_key {
+            Some(api_key) => api_key,
+            None if url_changed => return Err(Error::Invalid(format!("The api key of {} {} must be given again to change its url", self.kind, self.id))),
+            None => self.api_key.clone()
+        };
+
+        let mut updated = ServiceConfig::new(&self.kind, &url, &api_key, is4k.unwrap_or(self.is4k));
+        updated.id = self.id;
+        updated.activity_source = activity_source.unwrap_or_else(|| self.activity_source.clone());
+        Ok(updated)
+    }
+
+    // create the instance if it has no id yet, update it otherwise
+    pub fn save(&mut self, conn: &Connection) -> Result<()> {
+        ServiceConfig::check_kind(&self.kind)?;
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(Error::Invalid(format!("Invalid url : {}", self.url)));
+        }
+        if self.kind == "jellyfin" && !JELLYFIN_ACTIVITY_SOURCES.contains(&self.activity_source.as_str()) {
+            return Err(Error::Invalid(format!("Invalid activity source : {}, expected one of {}", self.activity_source, JELLYFIN_ACTIVITY_SOURCES.join(", "))));
+        }
+
+        if self.id == 0 {
+            if ServiceConfig::has_is4k(&self.kind) {
+                conn.execute(&format!("INSERT INTO {} (url, api_key, is4k) VALUES (?, ?, ?)", self.kind), params![self.url, self.api_key, self.is4k])?;
+            } else {
+                conn.execute(&format!("INSERT INTO {} (url, api_key) VALUES (?, ?)", self.kind), params![self.url, self.api_key])?;
+            }
+            self.id = conn.last_insert_rowid() as i32;
+        } else if ServiceConfig::has_is4k(&self.kind) {
+            conn.execute(&format!("UPDATE {} SET url = ?, api_key = ?, is4k = ? WHERE id = ?", self.kind), params![self.url, self.api_key, self.is4k, self.id])?;
+        } else {
+            conn.execute(&format!("UPDATE {} SET url = ?, api_key = ? WHERE id = ?", self.kind), params![self.url, self.api_key, self.id])?;
+        }
+        if self.kind == "jellyfin" {
+            conn.execute("UPDATE jellyfin SET activity_source = ? WHERE id = ?", params![self.activity_source, self.id])?;
+        }
+
+        Ok(())
+    }
+
+    // register a radarr or sonarr found in the settings of a request manager, known by its server id there,
+    // or by its url when added by hand, an instance already discovered by another request manager is left to it
+    pub fn discover(conn: &Connection, kind: &str, discovered_by: &str, server_id: i64, url: &str, api_key: &str, is4k: bool) -> Result<ServiceConfig> {
+        let mut config = ServiceConfig::new(kind, url, api_key, is4k);
+        if !ServiceConfig::has_is4k(kind) {
+            return Err(Error::Invalid(format!("{} can not be discovered", kind)));
+        }
+
+        let known = conn.query_row(&format!("
+            SELECT id FROM {}
+            WHERE (discovered_by = ?1 AND server_id = ?2) OR (IFNULL(discovered_by, '') = '' AND url = ?3)
+            ORDER BY discovered_by = ?1 DESC
+            LIMIT 1
+        ", kind), params![discovered_by, server_id, config.url], |row| row.get(0)).optional()?;
+
+        config.id = match known {
+            Some(id) => id,
+            None => {
+                let owner: Option<(i32, String)> = conn.query_row(&format!("SELECT id, IFNULL(discovered_by, '') FROM {} WHERE url = ?", kind), [&config.url], |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+                if let Some((id, owner)) = owner {
+                    println!("{} {} : {} is already discovered by {}", kind, id, config.url, owner);
+                    return ServiceConfig::get_by_id(conn, kind, id);
+                }
+                0
+            }
+        };
+        config.save(conn)?;
+
+        conn.execute(&format!("
+            UPDATE {} SET discovered_by = ?, server_id = ?
+            WHERE id = ?
+        ", kind), params![discovered_by, server_id, config.id])?;
+
+        Ok(config)
+    }
+
+    // delete the instances discovered from a request manager which are no longer in its settings
+    pub fn delete_undiscovered(conn: &Connection, kind: &str, discovered_by: &str, server_ids: &[i64]) -> Result<()> {
+        ServiceConfig::check_kind(kind)?;
+
+        let mut stmt = conn.prepare(&format!("SELECT id, server_id FROM {} WHERE discovered_by = ?", kind))?;
+        let instances = stmt.query_map([discovered_by], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i64>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for (id, _) in instances.into_iter().filter(|(_, server_id)| !server_ids.contains(server_id)) {
+            let config = ServiceConfig::get_by_id(conn, kind, id)?;
+            config.delete(conn)?;
+            println!("Removed {} {} : {}, no longer in {}", kind, id, config.url, discovered_by);
+        }
+
+        Ok(())
+    }
+
+    // save the configuration if the connection test succeeds, or anyway when forced
+    pub fn save_tested(&mut self, conn: &Connection, force: bool) -> Result<ConnectionTest> {
+        let test = self.test();
+        if test.is_success() || force {
+            self.save(conn)?;
+        }
+        Ok(test)
+    }
+
+    pub fn delete(&self, conn: &Connection) -> Result<()> {
+        // forget the root folders of the instance too
+        if ServiceConfig::has_is4k(&self.kind) {
+            conn.execute(&format!("DELETE FROM {kind}_path WHERE {kind}_id = ?", kind = self.kind), [self.id])?;
+        }
+        conn.execute(&format!("DELETE FROM {} WHERE id = ?", self.kind), [self.id])?;
+
+        Ok(())
+    }
+
+    // call the status endpoint of the service to check it is reachable and the api key is accepted
+    pub fn test(&self) -> ConnectionTest {
+        let mut test = ConnectionTest::default();
+
+        let (path, auth) = match self.kind.as_str() {
+            "overseerr" | "jellyseerr" => ("/api/v1/settings/main", ApiKey::Header("x-api-key")),
+            "radarr" | "sonarr" => ("/api/v3/system/status", ApiKey::Header("X-Api-Key")),
+            "jellyfin" => ("/System/Info", ApiKey::Header("X-Emby-Token")),
+            "tautulli" => ("/api/v2?cmd=get_tautulli_info", ApiKey::Query("apikey")),
+            kind => {
+                test.error = format!("Unknown service kind : {}", kind);
+                return test;
+            }
+        };
+        // a test answers right away, a service down is not retried
+        let client = HttpClient::new(&self.url, &self.api_key, auth).with_retries(0);
+
+        let json: serde_json::Value = match client.get(path).and_then(|body| Ok(serde_json::from_str(&body)?)) {
+            Ok(json) => json,
+            Err(err) => {
+                test.reachable = !matches!(err, Error::Timeout { .. } | Error::Unreachable { .. });
+                test.error = err.to_string();
+                return test;
+            }
+        };
+        test.reachable = true;
+
+        // tautulli answers invalid api keys with a success status
+        if self.kind == "tautulli" && json["response"]["result"].as_str() != Some("success") {
+            test.error = json["response"]["message"].as_str().unwrap_or("Invalid api key").to_string();
+            return test;
+        }
+        test.authenticated = true;
+
+        // the settings of overseerr and jellyseerr have no version, it is given by the public status
+        test.version = match self.kind.as_str() {
+            "overseerr" | "jellyseerr" => client.get("/api/v1/status")
+                .map(|body| serde_json::from_str::<serde_json::Value>(&body).unwrap_or_default()["version"].as_str().unwrap_or_default().to_string())
+                .unwrap_or_default(),
+            "jellyfin" => json["Version"].as_str().unwrap_or_default().to_string(),
+            "tautulli" => json["response"]["data"]["tautulli_version"].as_str().unwrap_or_default().to_string(),
+            _ => json["version"].as_str().unwrap_or_default().to_string()
+        };
+
+        test
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn radarr() -> ServiceConfig {
+        let mut config = ServiceConfig::new("radarr", "http://radarr:7878/", "secret", false);
+        config.id = 3;
+        config
+    }
+
+    #[test]
+    fn updates_keep_the_fields_not_given() {
+        let updated = radarr().update(None, None, Some(true), None).unwrap();
+        assert_eq!((updated.id, updated.url.as_str(), updated.api_key.as_str(), updated.is4k), (3, "http://radarr:7878", "secret", true));
+
+        let updated = radarr().update(Some("http://radarr:7878/".to_string()), None, None, None).unwrap();
+        assert_eq!(updated.api_key, "secret");
+    }
+
+    #[test]
+    fn the_api_key_is_not_reused_for_a_new_url() {
+        assert!(radarr().update(Some("http://other:7878".to_string()), None, None, None).is_err());
+
+        let updated = radarr().update(Some("http://other:7878".to_string()), Some("other".to_string()), None, None).unwrap();
+        assert_eq!((updated.url.as_str(), updated.api_key.as_str()), ("http://other:7878", "other"));
+    }
+}
@@ -1,14 +1,25 @@
-use super::{disk::Disk, serie::Serie};
+use std::collections::HashMap;
+
+use super::{disk::{Disk, DiskSpace}, serie::Serie};
+use super::backend::MediaManager;
+use super::http_client::{ApiKey, HttpClient};
 use super::path::SonarrPath;
-use reqwest::blocking::Client;
+use super::service_config::ServiceConfig;
 use serde::Deserialize;
-use rusqlite::{Connection, Result, params};
+use rusqlite::{Connection, params};
+
+use crate::error::{Error, Result};
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct RootFolder {
-    path: String,
-    freeSpace: u64,
-    id: i32
+    path: String
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    id: i64,
+    label: String
 }
 
 #[derive(Debug)]
@@ -20,63 +31,64 @@ pub struct Sonarr {
 }
 
 impl Sonarr {
-    pub fn new(url: &str, api_key: &str, is4k: bool) -> Self {
-        let conn = Connection::open("data.db").unwrap();
-
-        conn.execute("REPLACE INTO sonarr (url, api_key, is4k) VALUES (?, ?, ?)", params![url, api_key, is4k]).unwrap();
+    // register an instance discovered from a request manager, updated in place when already known
+    pub fn new(conn: &Connection, discovered_by: &str, server_id: i64, url: &str, api_key: &str, is4k: bool) -> Result<Self> {
+        let config = ServiceConfig::discover(conn, "sonarr", discovered_by, server_id, url, api_key, is4k)?;
+
+        Ok(Sonarr {
+            id: config.id,
+            url: config.url,
+            api_key: config.api_key,
+            is4k: config.is4k
+        })
+    }
 
-        Sonarr {
-            id: i32::from(conn.last_insert_rowid() as i32),
-            url: url.to_string(),
-            api_key: api_key.to_string(),
-            is4k: is4k
-        }
+    fn client(&self) -> HttpClient {
+        HttpClient::new(&self.url, &self.api_key, ApiKey::Header("X-Api-Key"))
     }
 
-    fn reqwest_get(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let client = Client::new();
-        let response = client.get(url).header("X-Api-Key", &self.api_key).send()?;
-        
-        if !response.status().is_success() {
-            return Err(format!("Request failed: {}", response.status()).into());
-        }
+    pub fn get_id(&self) -> i32 {
+        self.id
+    }
 
-        response.text().map_err(|err| err.into())
+    pub fn get_url(&self) -> &str {
+        &self.url
     }
 
-    pub fn populate_paths(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-        let url = format!("{}{}", self.url, "/api/v3/rootfolder");
-        let body = self.reqwest_get(url.as_str())?;
+    pub fn populate_paths(&self, conn: &Connection) -> Result<()> {
+        let body = self.client().get("/api/v3/rootfolder")?;
 
         let root_folders: Vec<RootFolder> = serde_json::from_str(&body)?;
-        let root_folders_iter = root_folders.iter();
+
+        let body = self.client().get("/api/v3/diskspace")?;
+        let disk_spaces: Vec<DiskSpace> = serde_json::from_str(&body)?;
 
         let mut paths: Vec<SonarrPath> = Vec::new();
 
-        for root_folder in root_folders_iter {
-            let disk = match Disk::get_by_free_space(root_folder.freeSpace) {
-                Ok(disk) => disk,
-                Err(_) => Disk::new(&conn, root_folder.freeSpace)
-            };
-            paths.push(SonarrPath::new(&conn, self.id, &root_folder.path, disk.get_id()));
+        for root_folder in root_folders.iter() {
+            // a root folder on no known mount point is kept without disk, it is left out of the disk pressure
+            let disk = Disk::from_root_folder(conn, &root_folder.path, &disk_spaces)?;
+            if disk.is_none() {
+                println!("No disk found for the root folder {}", root_folder.path);
+            }
+            paths.push(SonarrPath::new(conn, self.id, &root_folder.path, disk.map(|disk| disk.get_id()))?);
         };
 
         Ok(())
     }
 
+    // get every sonarr instance
     pub fn get_all(conn: &Connection) -> Result<Vec<Sonarr>> {
         let mut sonarrs: Vec<Sonarr> = Vec::new();
 
         let mut stmt = conn.prepare("SELECT id, url, api_key, is4k FROM sonarr")?;
         let sonarrs_iter = stmt.query_map([], |row| {
-            let mut sonarr = Sonarr {
+            Ok(Sonarr {
                 id: row.get(0)?,
                 url: row.get(1)?,
                 api_key: row.get(2)?,
                 is4k: row.get(3)?
-            };
-            sonarr.populate_paths(&conn).unwrap();
-            Ok(sonarr)
+            })
         })?;
         for sonarr in sonarrs_iter {
             sonarrs.push(sonarr?)
@@ -85,26 +97,79 @@ impl Sonarr {
         Ok(sonarrs)
     }
 
-    pub fn get_all_series(&self) -> Result<Vec<Serie>, Box<dyn std::error::Error>> {
+    // get the sonarr instance owning a root folder
+    pub fn get_by_path(conn: &Connection, path: &str, is4k: bool) -> Result<Sonarr> {
+        let mut stmt = conn.prepare("
+            SELECT sonarr.id, sonarr.url, sonarr.api_key, sonarr.is4k
+            FROM sonarr
+            INNER JOIN sonarr_path ON sonarr_path.sonarr_id = sonarr.id
+            WHERE sonarr_path.path = ? AND sonarr.is4k = ?
+        ")?;
+
+        let mut sonarr_iter = stmt.query_map(params![path, is4k], |row| {
+            Ok(Sonarr {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                api_key: row.get(2)?,
+                is4k: row.get(3)?
+            })
+        })?;
+
+        if let Some(result) = sonarr_iter.next() {
+            result.map_err(|err| err.into())
+        } else {
+            Err(Error::NotFound(format!("Sonarr for the path {}", path)))
+        }
+    }
+
+    // get the sonarr internal id of a serie from his tvdb id
+    pub fn get_serie_id(&self, tvdb_id: i32) -> Result<i64> {
+        let response = self.client().get(&format!("/api/v3/series?tvdbId={}", tvdb_id))?;
+        let json: serde_json::Value = serde_json::from_str(&response)?;
+
+        match json[0]["id"].as_i64() {
+            Some(id) => Ok(id),
+            None => Err(Error::NotFound(format!("Serie {} in sonarr {}", tvdb_id, self.url)))
+        }
+    }
+
+    // delete a serie and his files from sonarr
+    pub fn delete_serie(&self, tvdb_id: i32) -> Result<()> {
+        let id = self.get_serie_id(tvdb_id)?;
+        self.client().delete(&format!("/api/v3/series/{}?deleteFiles=true", id))
+    }
+
+    // get the names of the sonarr tags by id
+    pub fn get_tags(&self) -> Result<HashMap<i64, String>> {
+        let response = self.client().get("/api/v3/tag")?;
+        let tags: Vec<Tag> = serde_json::from_str(&response)?;
+
+        Ok(tags.into_iter().map(|tag| (tag.id, tag.label)).collect())
+    }
+
+    pub fn get_all_series(&self) -> Result<Vec<Serie>> {
         let mut series: Vec<Serie> = Vec::new();
-        let url = format!("{}/api/v3/series", self.url);
-        let response = self.reqwest_get(url.as_str())?;
+        let response = self.client().get("/api/v3/series")?;
         let json: serde_json::Value = serde_json::from_str(&response)?;
+        let tags = self.get_tags()?;
 
-        let series_json = json.as_array().unwrap();
+        let series_json = json.as_array().ok_or_else(|| Error::missing_field("series", "sonarr series"))?;
         for serie_json in series_json {
-            if serie_json["tvdbId"].is_null() || serie_json["statistics"]["episodeFileCount"].as_i64().unwrap() == 0 {
+            if serie_json["tvdbId"].is_null() || serie_json["statistics"]["episodeFileCount"].as_i64().unwrap_or(0) == 0 {
                 continue;
             }
-            series.push(Serie::from_sonarr_json(serie_json, self.is4k));
+            match Serie::from_sonarr_json(serie_json, self.is4k, &tags) {
+                Ok(serie) => series.push(serie),
+                Err(err) => println!("Skipping sonarr serie : {}", err)
+            };
         }
 
         Ok(series)
     }
 
-    pub fn update_db_series(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn update_db_series(&self, conn: &Connection) -> Result<()> {
         let sonarr_series = self.get_all_series()?;
-        let mut db_series = Serie::get_all(&conn)?;
+        let mut db_series = Serie::get_all(conn)?;
 
         let mut quantity_created = 0;
         let mut quantity_updated = 0;
@@ -113,31 +178,58 @@ impl Sonarr {
             if let Some(db_serie) = db_series.iter_mut().find(|db_serie| db_serie.tvdb_id == sonarr_serie.tvdb_id) {
                 let mut changed = false;
 
+                // the serie has been downloaded again since it was deleted
+                if db_serie.deleted {
+                    db_serie.deleted = false;
+                    db_serie.last_view = chrono::Utc::now().timestamp() as i32;
+                    db_serie.last_view_source = "sonarr".to_string();
+                    db_serie.added = db_serie.last_view;
+                    changed = true;
+                }
+
+                // keep the first known added date
+                if db_serie.added == 0 && sonarr_serie.added != 0 {
+                    db_serie.added = sonarr_serie.added;
+                    changed = true;
+                }
+
                 // update name if changed
                 if db_serie.name != sonarr_serie.name {
                     db_serie.name = sonarr_serie.name.clone();
                     changed = true;
                 }
 
-                // if is4k = true, update path_4k if changed
-                if self.is4k && db_serie.path_4k != sonarr_serie.path_4k {
+                // if is4k = true, update path_4k and size_4k if changed
+                if self.is4k && (db_serie.path_4k != sonarr_serie.path_4k || db_serie.size_4k != sonarr_serie.size_4k) {
                     db_serie.path_4k = sonarr_serie.path_4k.clone();
+                    db_serie.size_4k = sonarr_serie.size_4k;
                     changed = true;
                 }
 
-                // if is4k = false, update path_hd if changed
-                if !self.is4k && db_serie.path_hd != sonarr_serie.path_hd {
+                // if is4k = false, update path_hd and size_hd if changed
+                if !self.is4k && (db_serie.path_hd != sonarr_serie.path_hd || db_serie.size_hd != sonarr_serie.size_hd) {
                     db_serie.path_hd = sonarr_serie.path_hd.clone();
+                    db_serie.size_hd = sonarr_serie.size_hd;
+                    changed = true;
+                }
+
+                // update the tags of the copy if changed
+                if self.is4k && db_serie.tags_4k != sonarr_serie.tags_4k {
+                    db_serie.tags_4k = sonarr_serie.tags_4k.clone();
+                    changed = true;
+                }
+                if !self.is4k && db_serie.tags_hd != sonarr_serie.tags_hd {
+                    db_serie.tags_hd = sonarr_serie.tags_hd.clone();
                     changed = true;
                 }
 
                 // if changed, update db
                 if changed {
-                    db_serie.save(&conn)?;
+                    db_serie.save(conn)?;
                     quantity_updated += 1;
                 }
             } else {
-                sonarr_serie.save(&conn)?;
+                sonarr_serie.save(conn)?;
                 quantity_created += 1;
             }
         }
@@ -146,4 +238,18 @@ impl Sonarr {
         println!("Updated series : {}", quantity_updated);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl MediaManager for Sonarr {
+    fn get_name(&self) -> &str {
+        "sonarr"
+    }
+
+    fn populate_paths(&self, conn: &Connection) -> Result<()> {
+        Sonarr::populate_paths(self, conn)
+    }
+
+    fn update_db_medias(&self, conn: &Connection) -> Result<()> {
+        self.update_db_series(conn)
+    }
+}
@@ -1,38 +1,32 @@
+use std::collections::{HashMap, HashSet};
+
 use rusqlite::{Connection, params};
 
-use super::{movie::Movie, serie::Serie};
+use super::backend::ActivitySource;
+use super::http_client::{ApiKey, HttpClient};
+use super::{movie::Movie, plex_item::PlexItem, serie::Serie};
+
+use crate::error::{Error, Result};
+
+// the history and the libraries are read by pages, the history from the oldest play
+const HISTORY_PAGE_LENGTH: usize = 1000;
+const LIBRARY_PAGE_LENGTH: usize = 1000;
+// in seconds, a rating key without tmdb or tvdb id is requested again after a week
+const UNRESOLVED_ITEM_TTL: i32 = 60 * 60 * 24 * 7;
 
 pub struct Tautulli {
     pub id: i32,
     pub url: String,
     pub api_key: String,
-    pub history: Option<serde_json::Value>
+    // the newest play processed, the next runs only read the plays after it
+    pub last_history_id: i64,
+    pub last_history_date: i32
 }
 
 impl Tautulli {
-    pub fn new(conn: &Connection, url: &str, api_key: &str) -> Tautulli {
-        conn.execute("REPLACE INTO tautulli (url, api_key) VALUES (?, ?)", params![url, api_key]).unwrap();
-        let tautulli = Tautulli {
-            id: i32::from(conn.last_insert_rowid() as i32),
-            url: url.to_string(),
-            api_key: api_key.to_string(),
-            history: None
-        };
-        tautulli
-    }
-
-    pub fn save(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-        conn.execute("
-            REPLACE INTO tautulli (url, api_key)
-            VALUES (?1, ?2)",
-            params![self.url, self.api_key]
-        )?;
-        Ok(())
-    }
-
-    pub fn get_all(conn: &Connection) -> Result<Vec<Tautulli>, Box<dyn std::error::Error>> {
+    pub fn get_all(conn: &Connection) -> Result<Vec<Tautulli>> {
         let mut stmt = conn.prepare("
-            SELECT id, url, api_key
+            SELECT id, url, api_key, last_history_id, last_history_date
             FROM tautulli
         ")?;
         let tautullis = stmt.query_map([], |row| {
@@ -40,7 +34,8 @@ impl Tautulli {
                 id: row.get(0)?,
                 url: row.get(1)?,
                 api_key: row.get(2)?,
-                history: None
+                last_history_id: row.get(3)?,
+                last_history_date: row.get(4)?
             })
         })?;
 
@@ -51,78 +46,279 @@ impl Tautulli {
         Ok(tautullis_vec)
     }
 
-    fn reqwest_get(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let client = reqwest::blocking::Client::new();
-        let response = client.get(url).send()?;
-        
-        if !response.status().is_success() {
-            return Err(format!("Request failed: {}", response.status()).into());
+    fn client(&self) -> HttpClient {
+        HttpClient::new(&self.url, &self.api_key, ApiKey::Query("apikey"))
+    }
+
+    // get the rating keys of the movies and shows of the plex libraries, with the media type they are stored as
+    // and the tmdb or tvdb id of their guid when plex gives it
+    fn get_library_items(&self) -> Result<Vec<(String, &'static str, Option<i32>)>> {
+        let response = self.client().get("/api/v2?cmd=get_libraries")?;
+        let results: serde_json::Value = serde_json::from_str(&response)?;
+
+        let mut items = Vec::new();
+        for library in results["response"]["data"].as_array().ok_or_else(|| Error::missing_field("data", "tautulli libraries"))? {
+            let media_type = match library["section_type"].as_str() {
+                Some("movie") => "movie",
+                Some("show") => "serie",
+                _ => continue
+            };
+            let section_id = get_key(&library["section_id"]).ok_or_else(|| Error::missing_field("section_id", "tautulli libraries"))?;
+
+            let mut start = 0;
+            loop {
+                let response = self.client().get(&format!(
+                    "/api/v2?cmd=get_library_media_info&section_id={}&start={}&length={}",
+                    section_id, start, LIBRARY_PAGE_LENGTH
+                ))?;
+                let results: serde_json::Value = serde_json::from_str(&response)?;
+                let page = results["response"]["data"]["data"].as_array().ok_or_else(|| Error::missing_field("data", "tautulli library media info"))?;
+
+                items.extend(page.iter().filter_map(|item| {
+                    let rating_key = get_key(&item["rating_key"])?;
+                    Some((rating_key, media_type, item["guid"].as_str().and_then(|guid| parse_guid(guid, media_type))))
+                }));
+
+                start += page.len();
+                if page.len() < LIBRARY_PAGE_LENGTH {
+                    break;
+                }
+            }
         }
 
-        response.text().map_err(|err| err.into())
+        Ok(items)
     }
 
-    fn get_history(&mut self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-        if self.history.is_none() {
-            self.update_history()?;
+    // get the tmdb id of a movie or the tvdb id of a show from its plex metadata, for the guids of the plex agent
+    fn get_media_id(&self, rating_key: &str, media_type: &str) -> Result<i32> {
+        let response = self.client().get(&format!("/api/v2?cmd=get_metadata&rating_key={}", rating_key))?;
+        let results: serde_json::Value = serde_json::from_str(&response)?;
+
+        results["response"]["data"]["guids"].as_array()
+            .and_then(|guids| guids.iter().filter_map(|guid| guid.as_str()).find_map(|guid| parse_guid(guid, media_type)))
+            .ok_or_else(|| Error::missing_field(if media_type == "movie" { "tmdb" } else { "tvdb" }, "tautulli metadata"))
+    }
+
+    // link the movies and series to the rating keys of the plex libraries, plex gives new ones when a library is rescanned
+    pub fn update_rating_keys(&self, conn: &Connection) -> Result<()> {
+        let library_items = self.get_library_items()?;
+        let rating_keys: HashSet<&str> = library_items.iter().map(|(rating_key, _, _)| rating_key.as_str()).collect();
+
+        // the new rating keys are resolved once, or again after a while when they had no tmdb or tvdb id,
+        // the metadata is only requested when the library guid is not a tmdb or tvdb one
+        let now = chrono::Utc::now().timestamp() as i32;
+        let known_items = PlexItem::get_all(conn, self.id)?;
+        let known_keys: HashSet<&str> = known_items.iter()
+            .filter(|item| item.is_resolved() || item.date >= now - UNRESOLVED_ITEM_TTL)
+            .map(|item| item.rating_key.as_str())
+            .collect();
+        let mut new_items = Vec::new();
+        for (rating_key, media_type, media_id) in library_items.iter().filter(|(rating_key, _, _)| !known_keys.contains(rating_key.as_str())) {
+            let media_id = match media_id {
+                Some(media_id) => Ok(*media_id),
+                None => self.get_media_id(rating_key, media_type)
+            };
+            new_items.push(match media_id {
+                Ok(media_id) => PlexItem { tautulli_id: self.id, rating_key: rating_key.clone(), media_type: media_type.to_string(), media_id, date: now },
+                Err(err @ Error::MissingField { .. }) => {
+                    println!("Skipping tautulli item {} : {}", rating_key, err);
+                    PlexItem { tautulli_id: self.id, rating_key: rating_key.clone(), media_type: String::new(), media_id: 0, date: now }
+                },
+                Err(err) => {
+                    println!("Skipping tautulli item {} : {}", rating_key, err);
+                    continue;
+                }
+            });
+        }
+
+        // the items and the medias are saved together, those gone from the libraries are forgotten
+        let tx = conn.unchecked_transaction()?;
+        for item in &new_items {
+            item.save(&tx)?;
+        }
+        for item in known_items.iter().filter(|item| !rating_keys.contains(item.rating_key.as_str())) {
+            item.delete(&tx)?;
         }
-        Ok(self.history.clone().unwrap_or_default())
+
+        // a rating key still in the libraries is kept, a movie can be in several of them
+        let mut quantity_updated = 0;
+        for item in PlexItem::get_all(&tx, self.id)?.into_iter().filter(|item| item.is_resolved()) {
+            if item.media_type == "movie" {
+                let mut movie = match Movie::get_by_tmdb_id(&tx, item.media_id) {
+                    Ok(movie) => movie,
+                    Err(_) => continue
+                };
+                if !rating_keys.contains(movie.rating_key.as_str()) {
+                    movie.rating_key = item.rating_key;
+                    movie.save(&tx)?;
+                    quantity_updated += 1;
+                }
+            } else {
+                let mut serie = match Serie::get_by_tvdb_id(&tx, item.media_id) {
+                    Ok(serie) => serie,
+                    Err(_) => continue
+                };
+                if !rating_keys.contains(serie.rating_key.as_str()) {
+                    serie.rating_key = item.rating_key;
+                    serie.save(&tx)?;
+                    quantity_updated += 1;
+                }
+            }
+        }
+        tx.commit()?;
+
+        println!("Updated rating keys : {}", quantity_updated);
+        Ok(())
     }
 
-    fn update_history(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let url = format!("{}{}{}", self.url, "/api/v2?cmd=get_history&length=500000&apikey=", self.api_key);
-        let response = self.reqwest_get(url.as_str())?;
+    // get a page of plays, the oldest first
+    fn get_history_page(&self, after: &str, start: usize) -> Result<Vec<serde_json::Value>> {
+        let response = self.client().get(&format!(
+            "/api/v2?cmd=get_history&grouping=0&order_column=date&order_dir=asc&start={}&length={}{}",
+            start, HISTORY_PAGE_LENGTH, after
+        ))?;
         let results: serde_json::Value = serde_json::from_str(&response)?;
-        println!("Quantity founded : {:?}", results["response"]["data"]["data"].as_array().unwrap().len());
-        self.history = Some(results);
+        results["response"]["data"]["data"].as_array().cloned().ok_or_else(|| Error::missing_field("data", "tautulli history"))
+    }
+
+    // read the plays not processed yet page by page, each page being saved before the next one is read
+    pub fn update_medias_activity(&mut self, conn: &Connection) -> Result<()> {
+        // tautulli filters on the day in its own timezone, the plays of the day before are read again and skipped by id
+        let after = match chrono::DateTime::from_timestamp(self.last_history_date as i64 - 24 * 60 * 60, 0) {
+            Some(date) if self.last_history_date > 0 => format!("&after={}", date.format("%Y-%m-%d")),
+            _ => String::new()
+        };
+
+        let (mut quantity_plays, mut quantity_updated) = (0, 0);
+        let mut start = 0;
+        loop {
+            let page = self.get_history_page(&after, start)?;
+            let plays: Vec<&serde_json::Value> = page.iter().filter(|play| play["id"].as_i64().is_none_or(|id| id > self.last_history_id)).collect();
+            quantity_plays += plays.len();
+            quantity_updated += self.save_plays(conn, &plays)?;
+
+            start += page.len();
+            if page.len() < HISTORY_PAGE_LENGTH {
+                break;
+            }
+        }
+
+        println!("New plays : {}", quantity_plays);
+        println!("Updated medias : {}", quantity_updated);
         Ok(())
     }
 
-    pub fn update_medias_activity(&mut self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-        let history = self.get_history()?;
+    // update the last view of the medias played and remember the last play, together so a failed page is read again
+    fn save_plays(&mut self, conn: &Connection, plays: &[&serde_json::Value]) -> Result<usize> {
+        // the newest play of every media, so each one is looked up once
+        let mut last_views: HashMap<(bool, String), i32> = HashMap::new();
+        let (mut last_history_id, mut last_history_date) = (self.last_history_id, self.last_history_date);
+        for activity in plays {
+            last_history_id = last_history_id.max(activity["id"].as_i64().unwrap_or(0));
+
+            let (rating_key, last_view) = match parse_activity(activity) {
+                Ok(activity) => activity,
+                Err(err) => {
+                    println!("Skipping tautulli activity : {}", err);
+                    continue;
+                }
+            };
+            last_history_date = last_history_date.max(last_view);
+
+            let media_last_view = last_views.entry((activity["media_type"] == "movie", rating_key)).or_insert(last_view);
+            *media_last_view = (*media_last_view).max(last_view);
+        }
+
+        let tx = conn.unchecked_transaction()?;
         let mut quantity_updated = 0;
-        for activity in history["response"]["data"]["data"].as_array().unwrap() {
-            // get the media from the rating key
-            let rating_key = activity["grandparent_rating_key"].as_i64().or(activity["parent_rating_key"].as_i64()).or(activity["rating_key"].as_i64()).unwrap().to_string();
-            if activity["media_type"] == "movie" {
-                let mut movie = match Movie::get_by_rating_key(conn, rating_key.as_str()) {
+        for ((is_movie, rating_key), last_view) in last_views {
+            if is_movie {
+                let mut movie = match self.get_movie(&tx, &rating_key) {
                     Ok(movie) => movie,
                     Err(_) => continue
                 };
-            
+
                 // update the movie last_view
-                let last_view = activity["date"].as_i64().unwrap() as i32;
-                if movie.last_view < last_view{
+                if movie.last_view < last_view {
                     movie.last_view = last_view;
-                    movie.save(conn)?;
+                    movie.last_view_source = "tautulli".to_string();
+                    movie.save(&tx)?;
                     quantity_updated += 1;
                 }
             } else {
-                let mut serie = match Serie::get_by_rating_key(conn, rating_key.as_str()) {
+                let mut serie = match self.get_serie(&tx, &rating_key) {
                     Ok(serie) => serie,
                     Err(_) => continue
                 };
-            
+
                 // update the serie last_view
-                let last_view = activity["date"].as_i64().unwrap() as i32;
-                if serie.last_view < last_view{
+                if serie.last_view < last_view {
                     serie.last_view = last_view;
-                    serie.save(conn)?;
+                    serie.last_view_source = "tautulli".to_string();
+                    serie.save(&tx)?;
                     quantity_updated += 1;
                 }
             }
         }
-        println!("Updated medias : {}", quantity_updated);
-        Ok(())
+        tx.execute("UPDATE tautulli SET last_history_id = ?, last_history_date = ? WHERE id = ?", params![last_history_id, last_history_date, self.id])?;
+        tx.commit()?;
+
+        self.last_history_id = last_history_id;
+        self.last_history_date = last_history_date;
+        Ok(quantity_updated)
+    }
+
+    // get the movie of a rating key, from the libraries or as known by overseerr
+    fn get_movie(&self, conn: &Connection, rating_key: &str) -> Result<Movie> {
+        match PlexItem::get_by_rating_key(conn, self.id, rating_key) {
+            Ok(item) if item.media_type == "movie" => Movie::get_by_tmdb_id(conn, item.media_id),
+            _ => Movie::get_by_rating_key(conn, rating_key)
+        }
+    }
+
+    // get the serie of a rating key, from the libraries or as known by overseerr
+    fn get_serie(&self, conn: &Connection, rating_key: &str) -> Result<Serie> {
+        match PlexItem::get_by_rating_key(conn, self.id, rating_key) {
+            Ok(item) if item.media_type == "serie" => Serie::get_by_tvdb_id(conn, item.media_id),
+            _ => Serie::get_by_rating_key(conn, rating_key)
+        }
     }
 }
 
-trait Or: Sized {
-    fn or(self, other: Self) -> Self;
+impl ActivitySource for Tautulli {
+    fn get_name(&self) -> &str {
+        "tautulli"
+    }
+
+    // the activity is updated even if the libraries can not be read
+    fn update_activity(&mut self, conn: &Connection) -> Result<()> {
+        let rating_keys = self.update_rating_keys(conn);
+        let activity = self.update_medias_activity(conn);
+        rating_keys?;
+        activity
+    }
 }
 
-impl<'a> Or for &'a str {
-    fn or(self, other: &'a str) -> &'a str {
-        if self.is_empty() { other } else { self }
+// get the rating key of the media (the show for an episode) and the date of an activity
+fn parse_activity(activity: &serde_json::Value) -> Result<(String, i32)> {
+    let rating_key = get_key(&activity["grandparent_rating_key"]).or_else(|| get_key(&activity["parent_rating_key"])).or_else(|| get_key(&activity["rating_key"]))
+        .ok_or_else(|| Error::missing_field("rating_key", "tautulli history"))?;
+    let date = activity["date"].as_i64().ok_or_else(|| Error::missing_field("date", "tautulli history"))?;
+    Ok((rating_key, date as i32))
+}
+
+// tautulli gives the keys as strings or numbers depending on its version, an empty string being no key
+fn get_key(key: &serde_json::Value) -> Option<String> {
+    key.as_str().filter(|key| !key.is_empty()).map(|key| key.to_string()).or_else(|| key.as_i64().map(|key| key.to_string()))
+}
+
+// get the tmdb id of a movie or the tvdb id of a show from a guid of the plex agent (tmdb://603)
+// or of the legacy agents (com.plexapp.agents.themoviedb://603?lang=en)
+fn parse_guid(guid: &str, media_type: &str) -> Option<i32> {
+    let (agent, id) = guid.split_once("://")?;
+    let providers: &[&str] = if media_type == "movie" { &["tmdb", "com.plexapp.agents.themoviedb"] } else { &["tvdb", "com.plexapp.agents.thetvdb"] };
+    if !providers.contains(&agent) {
+        return None;
     }
-}
\ No newline at end of file
+    id.split('?').next()?.parse().ok()
+}
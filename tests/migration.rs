@@ -0,0 +1,139 @@
+// run the lunager binary on databases of older and newer versions
+use std::path::Path;
+use std::process::{Command, Output};
+
+use rusqlite::Connection;
+
+const LATEST_VERSION: i32 = 17;
+
+fn lunager(database: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lunager"))
+        .current_dir(database.parent().unwrap())
+        .args(args)
+        .env("DATABASE_PATH", database)
+        .output()
+        .unwrap()
+}
+
+fn get_version(conn: &Connection) -> i32 {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+}
+
+fn get_column_type(conn: &Connection, table: &str, column: &str) -> String {
+    conn.query_row(&format!("SELECT type FROM pragma_table_info('{}') WHERE name = ?", table), [column], |row| row.get(0)).unwrap()
+}
+
+#[test]
+fn new_database_is_created_at_the_latest_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("lunager.db");
+
+    assert!(lunager(&database, &["service", "list"]).status.success());
+
+    let conn = Connection::open(&database).unwrap();
+    assert_eq!(get_version(&conn), LATEST_VERSION);
+    let indexes: i32 = conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name IN ('movie_rating_key', 'movie_name', 'serie_rating_key', 'serie_name')", [], |row| row.get(0)).unwrap();
+    assert_eq!(indexes, 4);
+
+    // the migrations are only applied once
+    let output = lunager(&database, &["service", "list"]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Applied migration"));
+}
+
+#[test]
+fn database_of_the_first_schema_is_migrated() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("lunager.db");
+
+    // the schema of the first release, without user_version
+    let conn = Connection::open(&database).unwrap();
+    conn.execute_batch("
+        CREATE TABLE overseerr (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, url TEXT, api_key TEXTf);
+        CREATE TABLE disk (id INTEGER PRIMARY KEY AUTOINCREMENT, free_space INTEGER);
+        CREATE TABLE radarr (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, url TEXT, api_key TEXT, is4k INTEGER);
+        CREATE TABLE radarr_path (radarr_id INTEGER, path TEXT, disk_id INTEGER, PRIMARY KEY (radarr_id, path));
+        CREATE TABLE movie (tmdb_id INTEGER PRIMARY KEY, name TEXT, path_hd TEXT, path_4k TEXT, rating_key TEXT, last_view INTERGER, protected INTEGER);
+        CREATE TABLE serie (tvdb_id INTEGER PRIMARY KEY, name TEXT, path_hd INTERGER, path_4k TEXT, rating_key TEXT, last_view INTERGER, protected INTEGER);
+        INSERT INTO overseerr (url, api_key) VALUES ('http://overseerr:5055', 'key');
+        INSERT INTO disk (free_space) VALUES (100);
+        INSERT INTO radarr (url, api_key, is4k) VALUES ('http://radarr:7878', 'key', 0);
+        INSERT INTO radarr_path VALUES (1, '/movies', 1);
+        INSERT INTO movie VALUES (603, 'The Matrix', '/movies', '', '100', 1700000000, 0);
+        INSERT INTO serie VALUES (81189, 'Breaking Bad', '/tv', '', '200', 1700000000, 0);
+    ").unwrap();
+    drop(conn);
+
+    let output = lunager(&database, &["service", "list"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("overseerr 1 : http://overseerr:5055"));
+
+    let conn = Connection::open(&database).unwrap();
+    assert_eq!(get_version(&conn), LATEST_VERSION);
+    assert_eq!(get_column_type(&conn, "overseerr", "api_key"), "TEXT");
+    assert_eq!(get_column_type(&conn, "movie", "last_view"), "INTEGER");
+    assert_eq!(get_column_type(&conn, "serie", "path_hd"), "TEXT");
+    assert_eq!(get_column_type(&conn, "serie", "last_view"), "INTEGER");
+    assert_eq!(get_column_type(&conn, "disk", "key"), "TEXT");
+
+    // the medias are kept, with the defaults of the added columns
+    let movie: (String, String, i32, String) = conn.query_row("SELECT name, path_hd, last_view, tags_hd FROM movie WHERE tmdb_id = 603", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+    assert_eq!(movie, ("The Matrix".to_string(), "/movies".to_string(), 1700000000, String::new()));
+    let serie: (String, i32) = conn.query_row("SELECT path_hd, deleted FROM serie WHERE tvdb_id = 81189", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    assert_eq!(serie, ("/tv".to_string(), 0));
+
+    // the disks only known by their free space are removed, they are discovered again from the root folders
+    let disks: i32 = conn.query_row("SELECT COUNT(*) FROM disk", [], |row| row.get(0)).unwrap();
+    assert_eq!(disks, 0);
+    let disk_id: Option<i32> = conn.query_row("SELECT disk_id FROM radarr_path WHERE path = '/movies'", [], |row| row.get(0)).unwrap();
+    assert_eq!(disk_id, None);
+    let free_space: i32 = conn.query_row("SELECT COUNT(*) FROM pragma_table_info('disk') WHERE name = 'free_space'", [], |row| row.get(0)).unwrap();
+    assert_eq!(free_space, 0);
+}
+
+#[test]
+fn duplicated_instances_are_merged() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("lunager.db");
+
+    // every discovery registered radarr again, the root folders pointing to any of them
+    let conn = Connection::open(&database).unwrap();
+    conn.execute_batch("
+        CREATE TABLE radarr (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, url TEXT, api_key TEXT, is4k INTEGER);
+        CREATE TABLE radarr_path (radarr_id INTEGER, path TEXT, disk_id INTEGER, PRIMARY KEY (radarr_id, path));
+        INSERT INTO radarr (url, api_key, is4k) VALUES ('http://radarr:7878', 'old-key', 0), ('http://radarr:7878', 'key', 0), ('http://radarr4k:7878', 'key', 1);
+        INSERT INTO radarr_path VALUES (1, '/movies', 1), (2, '/movies', 1), (1, '/old', 1), (9, '/gone', 1);
+    ").unwrap();
+    drop(conn);
+
+    assert!(lunager(&database, &["service", "list"]).status.success());
+
+    let conn = Connection::open(&database).unwrap();
+    let mut stmt = conn.prepare("SELECT id, api_key FROM radarr ORDER BY id").unwrap();
+    let radarrs: Vec<(i32, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(radarrs, vec![(2, "key".to_string()), (3, "key".to_string())]);
+
+    let mut stmt = conn.prepare("SELECT radarr_id, path FROM radarr_path ORDER BY path").unwrap();
+    let paths: Vec<(i32, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(paths, vec![(2, "/movies".to_string()), (2, "/old".to_string())]);
+
+    // an url can not be registered twice anymore
+    assert!(conn.execute("INSERT INTO radarr (url, api_key, is4k) VALUES ('http://radarr:7878', 'key', 0)", []).is_err());
+}
+
+#[test]
+fn newer_database_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("lunager.db");
+
+    let conn = Connection::open(&database).unwrap();
+    conn.pragma_update(None, "user_version", LATEST_VERSION + 1).unwrap();
+    drop(conn);
+
+    let output = lunager(&database, &["service", "list"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("upgrade lunager"));
+
+    let conn = Connection::open(&database).unwrap();
+    assert_eq!(get_version(&conn), LATEST_VERSION + 1);
+}
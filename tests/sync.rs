@@ -0,0 +1,601 @@
+// run the lunager binary against local stand-ins of the services and check the database it leaves
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use mockito::{Matcher, Mock, Server, ServerGuard};
+use rusqlite::Connection;
+use serde_json::json;
+use tempfile::TempDir;
+
+const DAY: i64 = 24 * 60 * 60;
+
+struct Harness {
+    dir: TempDir,
+    overseerr: ServerGuard,
+    radarr: ServerGuard,
+    sonarr: ServerGuard,
+    tautulli: ServerGuard,
+    jellyfin: ServerGuard
+}
+
+impl Harness {
+    // start the stand-ins and register them in a new database
+    fn new() -> Self {
+        let harness = Harness {
+            dir: tempfile::tempdir().unwrap(),
+            overseerr: Server::new(),
+            radarr: Server::new(),
+            sonarr: Server::new(),
+            tautulli: Server::new(),
+            jellyfin: Server::new()
+        };
+
+        for (kind, server, api_key) in [
+            ("overseerr", &harness.overseerr, "overseerr-key"),
+            ("tautulli", &harness.tautulli, "tautulli-key"),
+            ("jellyfin", &harness.jellyfin, "jellyfin-key")
+        ] {
+            let output = harness.lunager(&["service", "add", kind, &server.url(), api_key, "--force"], &[]);
+            assert!(output.status.success());
+        }
+
+        harness
+    }
+
+    fn lunager(&self, args: &[&str], envs: &[(&str, &str)]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_lunager"))
+            .current_dir(self.dir.path())
+            .args(args)
+            .env("DATABASE_PATH", self.path("lunager.db"))
+            .env("HTTP_RETRIES", "0")
+            .env_remove("DRY_RUN")
+            .env_remove("DELETION_MODE")
+            .env_remove("DELETION_DELAY_DAYS")
+            .envs(envs.iter().copied())
+            .output()
+            .unwrap()
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn conn(&self) -> Connection {
+        Connection::open(self.path("lunager.db")).unwrap()
+    }
+
+    // the plex guids of a rating key
+    fn mock_metadata(&mut self, rating_key: &str, guids: &[&str]) -> Mock {
+        self.tautulli.mock("GET", "/api/v2")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("cmd".into(), "get_metadata".into()),
+                Matcher::UrlEncoded("rating_key".into(), rating_key.into())
+            ]))
+            .with_body(json!({ "response": { "result": "success", "data": { "guids": guids } } }).to_string())
+            .expect_at_least(1)
+            .create()
+    }
+
+    fn mock_services(&mut self) -> Vec<Mock> {
+        let now = chrono::Utc::now().timestamp();
+        let radarr_url = self.radarr.url();
+        let sonarr_url = self.sonarr.url();
+
+        // every stand-in has to be called at least once
+        vec![
+            // overseerr seeds the medias available in plex and gives the radarr and sonarr instances
+            self.overseerr.mock("GET", "/api/v1/Media").match_query(Matcher::Any).match_header("x-api-key", "overseerr-key")
+                .with_body(json!({ "results": [
+                    { "mediaType": "movie", "tmdbId": 603, "ratingKey": "100", "createdAt": "2020-01-01T00:00:00.000Z" },
+                    { "mediaType": "movie", "tmdbId": 550, "ratingKey": "101", "createdAt": "2020-01-01T00:00:00.000Z" },
+                    { "mediaType": "tv", "tvdbId": 81189, "ratingKey": "200", "createdAt": "2020-01-01T00:00:00.000Z" },
+                    { "mediaType": "tv", "tvdbId": 79168, "ratingKey": "201", "createdAt": "2020-01-01T00:00:00.000Z" },
+                    // a malformed media is skipped
+                    { "mediaType": "movie", "ratingKey": "102", "createdAt": "2020-01-01T00:00:00.000Z" }
+                ] }).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.overseerr.mock("GET", "/api/v1/settings/radarr")
+                .with_body(json!([{ "id": 1, "externalUrl": radarr_url, "apiKey": "radarr-key", "is4k": false }]).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.overseerr.mock("GET", "/api/v1/settings/sonarr")
+                .with_body(json!([{ "id": 1, "externalUrl": sonarr_url, "apiKey": "sonarr-key", "is4k": false }]).to_string())
+                .expect_at_least(1)
+                .create(),
+
+            self.radarr.mock("GET", "/api/v3/rootfolder")
+                .with_body(json!([{ "path": "/movies", "freeSpace": 500 }]).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.radarr.mock("GET", "/api/v3/diskspace")
+                .with_body(json!([{ "path": "/movies", "label": "movies", "freeSpace": 500, "totalSpace": 2000 }]).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.radarr.mock("GET", "/api/v3/tag")
+                .with_body(json!([{ "id": 1, "label": "keep" }]).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.radarr.mock("GET", "/api/v3/movie").match_header("X-Api-Key", "radarr-key")
+                .with_body(json!([
+                    { "tmdbId": 603, "title": "The Matrix", "hasFile": true, "rootFolderPath": "/movies", "sizeOnDisk": 1000, "added": "2020-01-01T00:00:00Z", "tags": [] },
+                    { "tmdbId": 550, "title": "Fight Club", "hasFile": true, "rootFolderPath": "/movies", "sizeOnDisk": 2000, "added": "2020-01-01T00:00:00Z", "tags": [] },
+                    // not requested in overseerr, viewed in jellyfin
+                    { "tmdbId": 680, "title": "Pulp Fiction", "hasFile": true, "rootFolderPath": "/movies", "sizeOnDisk": 3000, "added": "2020-01-01T00:00:00Z", "tags": [] },
+                    // protected by its tag
+                    { "tmdbId": 13, "title": "Forrest Gump", "hasFile": true, "rootFolderPath": "/movies", "sizeOnDisk": 4000, "added": "2020-01-01T00:00:00Z", "tags": [1] },
+                    // not downloaded
+                    { "tmdbId": 278, "title": "The Shawshank Redemption", "hasFile": false, "rootFolderPath": "/movies", "tags": [] }
+                ]).to_string())
+                .expect_at_least(1)
+                .create(),
+
+            self.sonarr.mock("GET", "/api/v3/rootfolder")
+                .with_body(json!([{ "path": "/tv", "freeSpace": 800 }]).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.sonarr.mock("GET", "/api/v3/diskspace")
+                .with_body(json!([{ "path": "/tv", "label": "tv", "freeSpace": 800, "totalSpace": 4000 }]).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.sonarr.mock("GET", "/api/v3/tag")
+                .with_body("[]")
+                .expect_at_least(1)
+                .create(),
+            self.sonarr.mock("GET", "/api/v3/series").match_header("X-Api-Key", "sonarr-key")
+                .with_body(json!([
+                    { "tvdbId": 81189, "title": "Breaking Bad", "rootFolderPath": "/tv", "statistics": { "episodeFileCount": 62, "sizeOnDisk": 5000 }, "added": "2020-01-01T00:00:00Z", "tags": [] },
+                    { "tvdbId": 79168, "title": "Friends", "rootFolderPath": "/tv", "statistics": { "episodeFileCount": 236, "sizeOnDisk": 6000 }, "added": "2020-01-01T00:00:00Z", "tags": [] }
+                ]).to_string())
+                .expect_at_least(1)
+                .create(),
+
+            // the matrix and an episode of breaking bad were watched yesterday
+            self.tautulli.mock("GET", "/api/v2")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("cmd".into(), "get_history".into()),
+                    Matcher::UrlEncoded("apikey".into(), "tautulli-key".into())
+                ]))
+                .with_body(json!({ "response": { "result": "success", "data": { "data": [
+                    // the keys are strings or numbers depending on the tautulli version
+                    { "id": 3, "media_type": "movie", "rating_key": "100", "parent_rating_key": "", "grandparent_rating_key": "", "date": now - DAY },
+                    { "id": 2, "media_type": "episode", "rating_key": 2001, "parent_rating_key": 2000, "grandparent_rating_key": 200, "date": now - DAY },
+                    // an activity without date is skipped
+                    { "id": 1, "media_type": "movie", "rating_key": 101 }
+                ] } } }).to_string())
+                .expect_at_least(1)
+                .create(),
+            // the libraries link pulp fiction, only known by radarr, to its rating key
+            self.tautulli.mock("GET", "/api/v2").match_query(Matcher::UrlEncoded("cmd".into(), "get_libraries".into()))
+                .with_body(json!({ "response": { "result": "success", "data": [
+                    { "section_id": "1", "section_type": "movie" },
+                    { "section_id": 2, "section_type": "show" },
+                    { "section_id": "3", "section_type": "artist" }
+                ] } }).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.tautulli.mock("GET", "/api/v2")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("cmd".into(), "get_library_media_info".into()),
+                    Matcher::UrlEncoded("section_id".into(), "1".into())
+                ]))
+                .with_body(json!({ "response": { "result": "success", "data": { "data": [
+                    { "rating_key": "100", "guid": "com.plexapp.agents.themoviedb://603?lang=en" },
+                    { "rating_key": "103", "guid": "plex://movie/5d7768286f4521001ea9c8a6" }
+                ] } } }).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.tautulli.mock("GET", "/api/v2")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("cmd".into(), "get_library_media_info".into()),
+                    Matcher::UrlEncoded("section_id".into(), "2".into())
+                ]))
+                .with_body(json!({ "response": { "result": "success", "data": { "data": [{ "rating_key": 200, "guid": "com.plexapp.agents.thetvdb://81189?lang=en" }] } } }).to_string())
+                .expect_at_least(1)
+                .create(),
+            // only the guids of the plex agent need the metadata
+            self.mock_metadata("103", &["imdb://tt0110912", "tmdb://680"]),
+
+            // pulp fiction was watched yesterday, a removed movie before, both known by their item id
+            self.jellyfin.mock("POST", "/user_usage_stats/submit_custom_query").match_header("X-Emby-Token", "jellyfin-key")
+                .match_body(Matcher::Regex("'Movie'".into()))
+                .with_body(json!({ "results": [["pulp-fiction-item", (now - DAY).to_string()], ["removed-item", (now - 2 * DAY).to_string()]] }).to_string())
+                .expect_at_least(1)
+                .create(),
+            self.jellyfin.mock("POST", "/user_usage_stats/submit_custom_query")
+                .match_body(Matcher::Regex("'Episode'".into()))
+                .with_body(json!({ "results": [] }).to_string())
+                .expect_at_least(1)
+                .create(),
+            // the items are matched on their tmdb id, whatever their title
+            self.jellyfin.mock("GET", "/Items").match_header("X-Emby-Token", "jellyfin-key")
+                .match_query(Matcher::UrlEncoded("Ids".into(), "pulp-fiction-item,removed-item".into()))
+                .with_body(json!({ "Items": [
+                    { "Id": "pulp-fiction-item", "Name": "Pulp Fiction - Director's Cut", "Type": "Movie", "ProviderIds": { "Tmdb": "680" } }
+                ] }).to_string())
+                .expect_at_least(1)
+                .create()
+        ]
+    }
+}
+
+// the tmdb/tvdb ids of a table, with their last view source, deleted and protected flags
+fn get_medias(conn: &Connection, table: &str, id: &str) -> Vec<(i32, String, bool, bool)> {
+    let mut stmt = conn.prepare(&format!("SELECT {}, last_view_source, deleted, protected FROM {} ORDER BY {}", id, table, id)).unwrap();
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect()
+}
+
+// the radarr or sonarr instances, with the request manager which discovered them
+fn get_instances(conn: &Connection, kind: &str) -> Vec<(i32, String, String, bool, String, i64)> {
+    let mut stmt = conn.prepare(&format!("SELECT id, url, api_key, is4k, discovered_by, server_id FROM {} ORDER BY id", kind)).unwrap();
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect()
+}
+
+#[test]
+fn sync_stores_medias_disks_and_activity() {
+    let mut harness = Harness::new();
+    let mocks = harness.mock_services();
+    let radarr_delete = harness.radarr.mock("DELETE", Matcher::Any).expect(0).create();
+
+    let output = harness.lunager(&[], &[("DRY_RUN", "true")]);
+    assert!(output.status.success());
+    for mock in &mocks {
+        mock.assert();
+    }
+    radarr_delete.assert();
+
+    let conn = harness.conn();
+    assert_eq!(get_medias(&conn, "movie", "tmdb_id"), vec![
+        (13, "radarr".to_string(), false, true),
+        (550, "overseerr".to_string(), false, false),
+        (603, "tautulli".to_string(), false, false),
+        (680, "jellyfin".to_string(), false, false)
+    ]);
+    assert_eq!(get_medias(&conn, "serie", "tvdb_id"), vec![
+        (79168, "overseerr".to_string(), false, false),
+        (81189, "tautulli".to_string(), false, false)
+    ]);
+
+    // the jellyfin items are cached for their jellyfin, the removed one without media
+    let mut stmt = conn.prepare("SELECT jellyfin_id, item_id, media_type, media_id FROM jellyfin_item ORDER BY item_id").unwrap();
+    let items: Vec<(i32, String, String, i32)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(items, vec![
+        (1, "pulp-fiction-item".to_string(), "movie".to_string(), 680),
+        (1, "removed-item".to_string(), String::new(), 0)
+    ]);
+
+    // pulp fiction was only known by radarr, the matrix keeps the rating key given by overseerr
+    let mut stmt = conn.prepare("SELECT tmdb_id, rating_key FROM movie WHERE tmdb_id IN (603, 680) ORDER BY tmdb_id").unwrap();
+    let rating_keys: Vec<(i32, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(rating_keys, vec![(603, "100".to_string()), (680, "103".to_string())]);
+
+    let (name, path, size): (String, String, i64) = conn.query_row("SELECT name, path_hd, size_hd FROM movie WHERE tmdb_id = 550", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+    assert_eq!((name.as_str(), path.as_str(), size), ("Fight Club", "/movies", 2000));
+
+    let mut stmt = conn.prepare("SELECT path, label, total_space FROM disk ORDER BY path").unwrap();
+    let disks: Vec<(String, String, i64)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(disks, vec![
+        ("/movies".to_string(), "movies".to_string(), 2000),
+        ("/tv".to_string(), "tv".to_string(), 4000)
+    ]);
+    let free_spaces: i64 = conn.query_row("SELECT COUNT(*) FROM disk_space", [], |row| row.get(0)).unwrap();
+    assert_eq!(free_spaces, 2);
+
+    // only the medias without a recent view are reported
+    let report: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(harness.path("report.json")).unwrap()).unwrap();
+    let mut names: Vec<&str> = report["items"].as_array().unwrap().iter().map(|item| item["title"].as_str().unwrap()).collect();
+    names.sort();
+    assert_eq!(names, vec!["Fight Club", "Friends"]);
+}
+
+#[test]
+fn deletion_removes_medias_not_viewed() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+
+    let radarr_lookup = harness.radarr.mock("GET", "/api/v3/movie")
+        .match_query(Matcher::UrlEncoded("tmdbId".into(), "550".into()))
+        .with_body(json!([{ "id": 2 }]).to_string())
+        .create();
+    let radarr_delete = harness.radarr.mock("DELETE", "/api/v3/movie/2")
+        .match_query(Matcher::UrlEncoded("deleteFiles".into(), "true".into()))
+        .expect(1)
+        .create();
+    let sonarr_lookup = harness.sonarr.mock("GET", "/api/v3/series")
+        .match_query(Matcher::UrlEncoded("tvdbId".into(), "79168".into()))
+        .with_body(json!([{ "id": 7 }]).to_string())
+        .create();
+    // sonarr fails, the serie is kept to be deleted on the next pass
+    let sonarr_delete = harness.sonarr.mock("DELETE", "/api/v3/series/7")
+        .match_query(Matcher::Any)
+        .with_status(500)
+        .expect(1)
+        .create();
+
+    let output = harness.lunager(&[], &[]);
+    assert!(output.status.success());
+    radarr_lookup.assert();
+    radarr_delete.assert();
+    sonarr_lookup.assert();
+    sonarr_delete.assert();
+
+    let conn = harness.conn();
+    let deleted: Vec<i32> = get_medias(&conn, "movie", "tmdb_id").into_iter().filter(|movie| movie.2).map(|movie| movie.0).collect();
+    assert_eq!(deleted, vec![550]);
+    let deleted: Vec<i32> = get_medias(&conn, "serie", "tvdb_id").into_iter().filter(|serie| serie.2).map(|serie| serie.0).collect();
+    assert!(deleted.is_empty());
+
+    let mut stmt = conn.prepare("SELECT media_type, media_id, success FROM deletion ORDER BY media_type").unwrap();
+    let deletions: Vec<(String, i32, bool)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(deletions, vec![("movie".to_string(), 550, true), ("serie".to_string(), 79168, false)]);
+}
+
+#[test]
+fn discovery_keeps_one_instance_per_server() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+    let output = harness.lunager(&["service", "add", "radarr", "http://radarr.local:7878", "manual-key", "--force"], &[]);
+    assert!(output.status.success());
+
+    // syncing again finds the same instances
+    for _ in 0..2 {
+        assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+    }
+    let conn = harness.conn();
+    let radarrs = get_instances(&conn, "radarr");
+    assert_eq!(radarrs, vec![
+        (1, "http://radarr.local:7878".to_string(), "manual-key".to_string(), false, String::new(), 0),
+        (2, harness.radarr.url(), "radarr-key".to_string(), false, "overseerr 1".to_string(), 1)
+    ]);
+    assert_eq!(get_instances(&conn, "sonarr").len(), 1);
+    let paths: i64 = conn.query_row("SELECT COUNT(*) FROM radarr_path WHERE radarr_id = 2", [], |row| row.get(0)).unwrap();
+    assert_eq!(paths, 1);
+
+    // the radarr of overseerr gets a new api key and goes 4K, its sonarr is removed
+    let radarr_url = harness.radarr.url();
+    let _settings = [
+        harness.overseerr.mock("GET", "/api/v1/settings/radarr")
+            .with_body(json!([{ "id": 1, "externalUrl": radarr_url, "apiKey": "radarr-key-2", "is4k": true }]).to_string())
+            .create(),
+        harness.overseerr.mock("GET", "/api/v1/settings/sonarr")
+            .with_body("[]")
+            .create()
+    ];
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+
+    let radarrs = get_instances(&conn, "radarr");
+    assert_eq!(radarrs[1], (2, harness.radarr.url(), "radarr-key-2".to_string(), true, "overseerr 1".to_string(), 1));
+    assert_eq!(radarrs[0].2, "manual-key");
+    assert!(get_instances(&conn, "sonarr").is_empty());
+    let paths: i64 = conn.query_row("SELECT COUNT(*) FROM sonarr_path", [], |row| row.get(0)).unwrap();
+    assert_eq!(paths, 0);
+}
+
+#[test]
+fn instance_shared_by_request_managers_is_left_to_the_first() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+    // jellyseerr reports the same radarr and sonarr, with the same server ids
+    let overseerr_url = harness.overseerr.url();
+    let output = harness.lunager(&["service", "add", "jellyseerr", &overseerr_url, "overseerr-key", "--force"], &[]);
+    assert!(output.status.success());
+
+    for _ in 0..2 {
+        let output = harness.lunager(&[], &[("DRY_RUN", "true")]);
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("is already discovered by overseerr 1"));
+    }
+    let conn = harness.conn();
+    assert_eq!(get_instances(&conn, "radarr"), vec![
+        (1, harness.radarr.url(), "radarr-key".to_string(), false, "overseerr 1".to_string(), 1)
+    ]);
+    assert_eq!(get_instances(&conn, "sonarr").len(), 1);
+}
+
+#[test]
+fn unresolved_jellyfin_items_are_requested_again_after_a_week() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+
+    // the removed item is not requested on the next run, only once it is a week old
+    let removed = harness.jellyfin.mock("GET", "/Items").match_query(Matcher::UrlEncoded("Ids".into(), "removed-item".into()))
+        .with_body(json!({ "Items": [] }).to_string())
+        .expect(1)
+        .create();
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+    harness.conn().execute("UPDATE jellyfin_item SET date = date - 8 * ? WHERE item_id = 'removed-item'", [DAY]).unwrap();
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+    removed.assert();
+}
+
+#[test]
+fn jellyfin_activity_from_user_data() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+    let output = harness.lunager(&["service", "update", "jellyfin", "1", "--activity-source", "user_data", "--force"], &[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&harness.lunager(&["service", "list"], &[]).stdout).contains("(activity from user_data)"));
+
+    // the newest play of every user is kept, the playback reporting plugin would give a day ago
+    let now = chrono::Utc::now().timestamp();
+    let played = |timestamp: i64| chrono::DateTime::from_timestamp(timestamp, 0).unwrap().format("%Y-%m-%dT%H:%M:%S.0000000Z").to_string();
+    let _user_mocks = [
+        harness.jellyfin.mock("GET", "/Users").match_header("X-Emby-Token", "jellyfin-key")
+            .with_body(json!([{ "Id": "alice" }, { "Id": "bob" }]).to_string())
+            .create(),
+        harness.jellyfin.mock("GET", "/Users/alice/Items").match_query(Matcher::UrlEncoded("IncludeItemTypes".into(), "Movie".into()))
+            .with_body(json!({ "Items": [
+                { "Id": "pulp-fiction-item", "UserData": { "PlayCount": 1, "LastPlayedDate": played(now - 3 * DAY) } },
+                { "Id": "unplayed-item", "UserData": { "PlayCount": 0 } },
+                { "Id": "played-by-hand-item", "UserData": { "PlayCount": 1 } }
+            ], "TotalRecordCount": 3 }).to_string())
+            .create(),
+        harness.jellyfin.mock("GET", "/Users/bob/Items").match_query(Matcher::UrlEncoded("IncludeItemTypes".into(), "Movie".into()))
+            .with_body(json!({ "Items": [
+                { "Id": "pulp-fiction-item", "UserData": { "PlayCount": 2, "LastPlayedDate": played(now - DAY / 2) } },
+                { "Id": "played-by-hand-item", "UserData": { "PlayCount": 1 } }
+            ], "TotalRecordCount": 2 }).to_string())
+            .create(),
+        harness.jellyfin.mock("GET", Matcher::Regex("^/Users/[a-z]+/Items".into())).match_query(Matcher::UrlEncoded("IncludeItemTypes".into(), "Episode".into()))
+            .with_body(json!({ "Items": [], "TotalRecordCount": 0 }).to_string())
+            .create(),
+        harness.jellyfin.mock("GET", "/Items").match_query(Matcher::UrlEncoded("Ids".into(), "pulp-fiction-item".into()))
+            .with_body(json!({ "Items": [{ "Id": "pulp-fiction-item", "Type": "Movie", "ProviderIds": { "Tmdb": "680" } }] }).to_string())
+            .create()
+    ];
+    let output = harness.lunager(&[], &[("DRY_RUN", "true")]);
+    assert!(output.status.success());
+    // the items played by hand are summed up over the users
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Skipped jellyfin user data without last played date : 2"));
+    assert!(!stdout.contains("Skipping jellyfin user data"));
+
+    let conn = harness.conn();
+    let (last_view, source): (i64, String) = conn.query_row("SELECT last_view, last_view_source FROM movie WHERE tmdb_id = 680", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    assert_eq!((last_view, source.as_str()), (now - DAY / 2, "jellyfin"));
+}
+
+#[test]
+fn jellyfin_series_activity_only_moves_forward() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+    let tautulli_view: i64 = harness.conn().query_row("SELECT last_view FROM serie WHERE tvdb_id = 81189", [], |row| row.get(0)).unwrap();
+
+    // friends was watched two days ago, breaking bad ten days ago while tautulli saw it yesterday
+    let now = chrono::Utc::now().timestamp();
+    let _episode_mocks = [
+        harness.tautulli.mock("GET", "/api/v2").match_query(Matcher::UrlEncoded("cmd".into(), "get_history".into()))
+            .with_body(json!({ "response": { "result": "success", "data": { "data": [] } } }).to_string())
+            .create(),
+        harness.jellyfin.mock("POST", "/user_usage_stats/submit_custom_query").match_body(Matcher::Regex("'Episode'".into()))
+            .with_body(json!({ "results": [
+                ["friends-s01e01", (now - 5 * DAY).to_string()],
+                ["friends-s01e02", (now - 2 * DAY).to_string()],
+                ["breaking-bad-s01e01", (now - 10 * DAY).to_string()]
+            ] }).to_string())
+            .create(),
+        harness.jellyfin.mock("GET", "/Items").match_query(Matcher::UrlEncoded("Ids".into(), "friends-s01e01,friends-s01e02,breaking-bad-s01e01".into()))
+            .with_body(json!({ "Items": [
+                { "Id": "friends-s01e01", "Type": "Episode", "SeriesId": "friends" },
+                { "Id": "friends-s01e02", "Type": "Episode", "SeriesId": "friends" },
+                { "Id": "breaking-bad-s01e01", "Type": "Episode", "SeriesId": "breaking-bad" }
+            ] }).to_string())
+            .create(),
+        harness.jellyfin.mock("GET", "/Items").match_query(Matcher::UrlEncoded("Ids".into(), "breaking-bad,friends".into()))
+            .with_body(json!({ "Items": [
+                { "Id": "breaking-bad", "Type": "Series", "ProviderIds": { "Tvdb": "81189" } },
+                { "Id": "friends", "Type": "Series", "ProviderIds": { "Tvdb": "79168" } }
+            ] }).to_string())
+            .create()
+    ];
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+
+    let conn = harness.conn();
+    let mut stmt = conn.prepare("SELECT tvdb_id, last_view, last_view_source FROM serie ORDER BY tvdb_id").unwrap();
+    let series: Vec<(i32, i64, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(series, vec![
+        (79168, now - 2 * DAY, "jellyfin".to_string()),
+        (81189, tautulli_view, "tautulli".to_string())
+    ]);
+}
+
+#[test]
+fn tautulli_history_is_read_incrementally() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+
+    let conn = harness.conn();
+    let (last_history_id, last_history_date): (i64, i64) = conn.query_row("SELECT last_history_id, last_history_date FROM tautulli", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    assert_eq!(last_history_id, 3);
+    let matrix_view: i64 = conn.query_row("SELECT last_view FROM movie WHERE tmdb_id = 603", [], |row| row.get(0)).unwrap();
+    assert_eq!(last_history_date, matrix_view);
+
+    // the plays already processed are skipped, only fight club is new
+    conn.execute("UPDATE movie SET last_view = 0 WHERE tmdb_id = 603", []).unwrap();
+    let now = chrono::Utc::now().timestamp();
+    let history = harness.tautulli.mock("GET", "/api/v2")
+        .match_query(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("cmd".into(), "get_history".into()),
+            Matcher::Regex("after=[0-9]{4}-[0-9]{2}-[0-9]{2}".into())
+        ]))
+        .with_body(json!({ "response": { "result": "success", "data": { "data": [
+            { "id": 4, "media_type": "movie", "rating_key": 101, "date": now - DAY / 2 },
+            { "id": 3, "media_type": "movie", "rating_key": 100, "date": matrix_view }
+        ] } } }).to_string())
+        .expect(1)
+        .create();
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+    history.assert();
+
+    let mut stmt = conn.prepare("SELECT tmdb_id, last_view, last_view_source FROM movie WHERE tmdb_id IN (550, 603) ORDER BY tmdb_id").unwrap();
+    let movies: Vec<(i32, i64, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(movies, vec![
+        (550, now - DAY / 2, "tautulli".to_string()),
+        (603, 0, "tautulli".to_string())
+    ]);
+    let last_history_id: i64 = conn.query_row("SELECT last_history_id FROM tautulli", [], |row| row.get(0)).unwrap();
+    assert_eq!(last_history_id, 4);
+}
+
+#[test]
+fn rating_keys_follow_library_rescans() {
+    let mut harness = Harness::new();
+    let _mocks = harness.mock_services();
+    assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+
+    // plex gave the matrix a new rating key, its plays are attributed with it
+    let now = chrono::Utc::now().timestamp();
+    let rescan_mocks = [
+        harness.tautulli.mock("GET", "/api/v2")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("cmd".into(), "get_library_media_info".into()),
+                Matcher::UrlEncoded("section_id".into(), "1".into())
+            ]))
+            .with_body(json!({ "response": { "result": "success", "data": { "data": [
+                { "rating_key": "110", "guid": "com.plexapp.agents.themoviedb://603?lang=en" },
+                { "rating_key": "103", "guid": "plex://movie/5d7768286f4521001ea9c8a6" },
+                { "rating_key": "111", "guid": "plex://movie/5d776b59ad5437001f79c6f8" }
+            ] } } }).to_string())
+            .create(),
+        // a home video without tmdb id is only requested once
+        harness.tautulli.mock("GET", "/api/v2")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("cmd".into(), "get_metadata".into()),
+                Matcher::UrlEncoded("rating_key".into(), "111".into())
+            ]))
+            .with_body(json!({ "response": { "result": "success", "data": { "guids": [] } } }).to_string())
+            .expect(1)
+            .create(),
+        harness.tautulli.mock("GET", "/api/v2").match_query(Matcher::UrlEncoded("cmd".into(), "get_history".into()))
+            .with_body(json!({ "response": { "result": "success", "data": { "data": [
+                { "id": 4, "media_type": "movie", "rating_key": 110, "date": now - DAY / 2 }
+            ] } } }).to_string())
+            .create()
+    ];
+    for _ in 0..2 {
+        assert!(harness.lunager(&[], &[("DRY_RUN", "true")]).status.success());
+    }
+    rescan_mocks[1].assert();
+
+    let conn = harness.conn();
+    let movie: (String, i64, String) = conn.query_row("SELECT rating_key, last_view, last_view_source FROM movie WHERE tmdb_id = 603", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+    assert_eq!(movie, ("110".to_string(), now - DAY / 2, "tautulli".to_string()));
+
+    let mut stmt = conn.prepare("SELECT rating_key, media_type, media_id FROM plex_item ORDER BY rating_key").unwrap();
+    let items: Vec<(String, String, i32)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(|row| row.unwrap()).collect();
+    assert_eq!(items, vec![
+        ("103".to_string(), "movie".to_string(), 680),
+        ("110".to_string(), "movie".to_string(), 603),
+        ("111".to_string(), String::new(), 0),
+        ("200".to_string(), "serie".to_string(), 81189)
+    ]);
+}